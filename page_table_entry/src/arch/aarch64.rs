@@ -4,7 +4,7 @@ use aarch64_cpu::registers::MAIR_EL1;
 use core::fmt;
 use memory_addr::PhysAddr;
 
-use crate::{GenericPTE, MappingFlags};
+use crate::{GenericPTE, MappingFlags, SWAP_SHIFT};
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -34,6 +34,9 @@ bitflags::bitflags! {
         const AF =          1 << 10;
         /// The not global bit.
         const NG =          1 << 11;
+        /// The Dirty Bit Modifier: the descriptor is writable, and `AP_RO`
+        /// only tells whether it is clean (with hardware dirty state management).
+        const DBM =         1 <<  51;
         /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
         const CONTIGUOUS =  1 <<  52;
         /// The Privileged execute-never field.
@@ -117,7 +120,7 @@ impl From<DescriptorAttr> for MappingFlags {
             return Self::empty();
         }
         let mut flags = Self::READ;
-        if !attr.contains(DescriptorAttr::AP_RO) {
+        if !attr.contains(DescriptorAttr::AP_RO) || attr.contains(DescriptorAttr::DBM) {
             flags |= Self::WRITE;
         }
         #[cfg(not(feature = "arm-el2"))]
@@ -161,7 +164,9 @@ impl From<MappingFlags> for DescriptorAttr {
         if flags.contains(MappingFlags::READ) {
             attr |= Self::VALID;
         }
-        if !flags.contains(MappingFlags::WRITE) {
+        if flags.contains(MappingFlags::WRITE) {
+            attr |= Self::DBM;
+        } else {
             attr |= Self::AP_RO;
         }
         #[cfg(not(feature = "arm-el2"))]
@@ -199,6 +204,24 @@ pub struct A64PTE(u64);
 
 impl A64PTE {
     const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
+    const COW: u64 = 1 << 55; // reserved for software use
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = DescriptorAttr::VALID.bits()
+        | DescriptorAttr::NON_BLOCK.bits()
+        | DescriptorAttr::ATTR_INDX.bits()
+        | DescriptorAttr::AP_EL0.bits()
+        | DescriptorAttr::AP_RO.bits()
+        | DescriptorAttr::DBM.bits()
+        | DescriptorAttr::INNER.bits()
+        | DescriptorAttr::SHAREABLE.bits()
+        | DescriptorAttr::PXN.bits()
+        | DescriptorAttr::UXN.bits();
+    const SW_FIELDS: &[(u32, u32)] = &[(56, 3)]; // reserved for software use, except for COW
+    /// A swap entry is not valid, and has the page bit set but not the access
+    /// flag, which every page descriptor has.
+    const SWAP: u64 = DescriptorAttr::NON_BLOCK.bits();
+    const SWAP_MASK: u64 =
+        DescriptorAttr::VALID.bits() | DescriptorAttr::NON_BLOCK.bits() | DescriptorAttr::AF.bits();
 
     /// Creates an empty descriptor with all bits set to zero.
     pub const fn empty() -> Self {
@@ -228,11 +251,17 @@ impl GenericPTE for A64PTE {
         self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK)
     }
     fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
-        let mut attr = DescriptorAttr::from(flags) | DescriptorAttr::AF;
+        let mut attr = DescriptorAttr::from(flags);
         if !is_huge {
             attr |= DescriptorAttr::NON_BLOCK;
         }
-        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | attr.bits();
+        // `AF` is kept, and so is the dirty state (`AP_RO`) of entries that
+        // stay writable
+        let old = DescriptorAttr::from_bits_truncate(self.0);
+        if attr.contains(DescriptorAttr::DBM) && old.contains(DescriptorAttr::DBM) {
+            attr.set(DescriptorAttr::AP_RO, old.contains(DescriptorAttr::AP_RO));
+        }
+        self.0 = (self.0 & !Self::FLAGS_MASK) | attr.bits();
     }
 
     fn bits(self) -> usize {
@@ -250,6 +279,50 @@ impl GenericPTE for A64PTE {
     fn clear(&mut self) {
         self.0 = 0
     }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & DescriptorAttr::AF.bits() != 0
+    }
+    fn is_dirty(&self) -> bool {
+        // writable-clean entries are read-only until the hardware (or the
+        // permission fault handler) clears `AP_RO`
+        let attr = DescriptorAttr::from_bits_truncate(self.0);
+        attr.contains(DescriptorAttr::DBM) && !attr.contains(DescriptorAttr::AP_RO)
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !DescriptorAttr::AF.bits();
+    }
+    fn clear_dirty(&mut self) {
+        if self.0 & DescriptorAttr::DBM.bits() != 0 {
+            self.0 |= DescriptorAttr::AP_RO.bits();
+        }
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
 }
 
 impl fmt::Debug for A64PTE {
@@ -262,3 +335,269 @@ impl fmt::Debug for A64PTE {
             .finish()
     }
 }
+
+bitflags::bitflags! {
+    /// Memory attribute fields in the VMSAv8-64 stage 2 translation table format descriptors.
+    #[derive(Debug)]
+    pub struct S2DescriptorAttr: u64 {
+        /// Whether the descriptor is valid.
+        const VALID =       1 << 0;
+        /// The descriptor gives the address of the next level of translation table or 4KB page.
+        /// (not a 2M, 1G block)
+        const NON_BLOCK =   1 << 1;
+        /// Stage 2 memory attributes, encoded directly instead of through MAIR.
+        const MEM_ATTR =    0b1111 << 2;
+        /// Stage 2 access permission: readable.
+        const S2AP_R =      1 << 6;
+        /// Stage 2 access permission: writable.
+        const S2AP_W =      1 << 7;
+        /// Shareability: Inner Shareable (otherwise Outer Shareable).
+        const INNER =       1 << 8;
+        /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
+        const SHAREABLE =   1 << 9;
+        /// The Access flag.
+        const AF =          1 << 10;
+        /// The Dirty Bit Modifier: the descriptor is writable, and `S2AP_W`
+        /// only tells whether it is dirty (with hardware dirty state management).
+        const DBM =         1 << 51;
+        /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
+        const CONTIGUOUS =  1 << 52;
+        /// XN\[0\]: with FEAT_XNX, distinguishes execute-never at EL1 and EL0.
+        const XNX =         1 << 53;
+        /// XN\[1\]: the Execute-never field.
+        const XN =          1 << 54;
+    }
+}
+
+/// The stage 2 memory attributes field in the descriptor, MemAttr\[3:0\]
+/// (bit\[5:2\]).
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum S2MemAttr {
+    /// Device-nGnRE memory
+    Device = 0b0001,
+    /// Normal memory, Inner and Outer Write-Back Cacheable
+    Normal = 0b1111,
+    /// Normal memory, Inner and Outer Non-cacheable
+    NormalNonCacheable = 0b0101,
+}
+
+impl S2DescriptorAttr {
+    /// Constructs a descriptor from the stage 2 memory attributes, leaving
+    /// the other fields empty.
+    pub const fn from_mem_attr(attr: S2MemAttr) -> Self {
+        let mut bits = (attr as u64) << 2;
+        if matches!(attr, S2MemAttr::Normal | S2MemAttr::NormalNonCacheable) {
+            bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
+        }
+        Self::from_bits_retain(bits)
+    }
+
+    /// Returns the stage 2 memory attributes field.
+    pub const fn mem_attr(&self) -> Option<S2MemAttr> {
+        Some(match (self.bits() & Self::MEM_ATTR.bits()) >> 2 {
+            0b0001 => S2MemAttr::Device,
+            0b1111 => S2MemAttr::Normal,
+            0b0101 => S2MemAttr::NormalNonCacheable,
+            _ => return None,
+        })
+    }
+}
+
+impl From<S2DescriptorAttr> for MappingFlags {
+    fn from(attr: S2DescriptorAttr) -> Self {
+        if !attr.contains(S2DescriptorAttr::VALID) {
+            return Self::empty();
+        }
+        let mut flags = Self::empty();
+        if attr.contains(S2DescriptorAttr::S2AP_R) {
+            flags |= Self::READ;
+        }
+        if attr.intersects(S2DescriptorAttr::S2AP_W | S2DescriptorAttr::DBM) {
+            flags |= Self::WRITE;
+        }
+        if !attr.contains(S2DescriptorAttr::XN) {
+            flags |= Self::EXECUTE;
+        }
+        match attr.mem_attr() {
+            Some(S2MemAttr::Device) => flags |= Self::DEVICE,
+            Some(S2MemAttr::NormalNonCacheable) => flags |= Self::UNCACHED,
+            _ => {}
+        }
+        flags
+    }
+}
+
+impl From<MappingFlags> for S2DescriptorAttr {
+    fn from(flags: MappingFlags) -> Self {
+        if flags.is_empty() {
+            return Self::empty();
+        }
+        let mut attr = if flags.contains(MappingFlags::DEVICE) {
+            Self::from_mem_attr(S2MemAttr::Device)
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            Self::from_mem_attr(S2MemAttr::NormalNonCacheable)
+        } else {
+            Self::from_mem_attr(S2MemAttr::Normal)
+        };
+        attr |= Self::VALID;
+        if flags.contains(MappingFlags::READ) {
+            attr |= Self::S2AP_R;
+        }
+        if flags.contains(MappingFlags::WRITE) {
+            attr |= Self::S2AP_W | Self::DBM;
+        }
+        if !flags.contains(MappingFlags::EXECUTE) {
+            attr |= Self::XN;
+        }
+        attr
+    }
+}
+
+/// A VMSAv8-64 stage 2 translation table descriptor, which translates
+/// intermediate physical addresses (IPA) to physical addresses.
+///
+/// Memory attributes are encoded directly in the descriptor, so no MAIR
+/// configuration is needed. [`MappingFlags::USER`] has no meaning at stage 2
+/// and is ignored.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct A64S2PTE(u64);
+
+impl A64S2PTE {
+    const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
+    const COW: u64 = 1 << 55; // reserved for software use
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = S2DescriptorAttr::VALID.bits()
+        | S2DescriptorAttr::NON_BLOCK.bits()
+        | S2DescriptorAttr::MEM_ATTR.bits()
+        | S2DescriptorAttr::S2AP_R.bits()
+        | S2DescriptorAttr::S2AP_W.bits()
+        | S2DescriptorAttr::DBM.bits()
+        | S2DescriptorAttr::INNER.bits()
+        | S2DescriptorAttr::SHAREABLE.bits()
+        | S2DescriptorAttr::XNX.bits()
+        | S2DescriptorAttr::XN.bits();
+    const SW_FIELDS: &[(u32, u32)] = &[(56, 3)]; // reserved for software use, except for COW
+    /// A swap entry is not valid, and has the page bit set but not the access
+    /// flag, which every page descriptor has.
+    const SWAP: u64 = S2DescriptorAttr::NON_BLOCK.bits();
+    const SWAP_MASK: u64 = S2DescriptorAttr::VALID.bits()
+        | S2DescriptorAttr::NON_BLOCK.bits()
+        | S2DescriptorAttr::AF.bits();
+
+    /// Creates an empty descriptor with all bits set to zero.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+}
+
+impl GenericPTE for A64S2PTE {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut attr = S2DescriptorAttr::from(flags) | S2DescriptorAttr::AF;
+        if !is_huge {
+            attr |= S2DescriptorAttr::NON_BLOCK;
+        }
+        Self(attr.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        let attr = S2DescriptorAttr::NON_BLOCK | S2DescriptorAttr::VALID;
+        Self(attr.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        S2DescriptorAttr::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK)
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut attr = S2DescriptorAttr::from(flags);
+        if !is_huge {
+            attr |= S2DescriptorAttr::NON_BLOCK;
+        }
+        // `AF` is kept, and so is the dirty state (`S2AP_W`) of entries that
+        // stay writable
+        let old = S2DescriptorAttr::from_bits_truncate(self.0);
+        if attr.contains(S2DescriptorAttr::DBM) && old.contains(S2DescriptorAttr::DBM) {
+            attr.set(
+                S2DescriptorAttr::S2AP_W,
+                old.contains(S2DescriptorAttr::S2AP_W),
+            );
+        }
+        self.0 = (self.0 & !Self::FLAGS_MASK) | attr.bits();
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        S2DescriptorAttr::from_bits_truncate(self.0).contains(S2DescriptorAttr::VALID)
+    }
+    fn is_huge(&self) -> bool {
+        !S2DescriptorAttr::from_bits_truncate(self.0).contains(S2DescriptorAttr::NON_BLOCK)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & S2DescriptorAttr::AF.bits() != 0
+    }
+    fn is_dirty(&self) -> bool {
+        // writable-clean entries are read-only until the hardware (or the
+        // permission fault handler) sets `S2AP_W`
+        let attr = S2DescriptorAttr::from_bits_truncate(self.0);
+        attr.contains(S2DescriptorAttr::DBM | S2DescriptorAttr::S2AP_W)
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !S2DescriptorAttr::AF.bits();
+    }
+    fn clear_dirty(&mut self) {
+        if self.0 & S2DescriptorAttr::DBM.bits() != 0 {
+            self.0 &= !S2DescriptorAttr::S2AP_W.bits();
+        }
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
+}
+
+impl fmt::Debug for A64S2PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("A64S2PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("attr", &S2DescriptorAttr::from_bits_truncate(self.0))
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
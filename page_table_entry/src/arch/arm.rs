@@ -0,0 +1,401 @@
+//! ARMv7-A VMSAv7 short-descriptor translation table format descriptors.
+//!
+//! The descriptors are built for TEX remap (`SCTLR.TRE = 1`, with `PRRR` and
+//! `NMRR` set to [`MemAttr::PRRR_VALUE`] and [`MemAttr::NMRR_VALUE`]) and
+//! with the access flag disabled (`SCTLR.AFE = 0`).
+//!
+//! First-level section descriptors and second-level small page descriptors
+//! use the same descriptor type bits. Under TEX remap, `TEX[2:1]` are left to
+//! software, so [`A32PTE`] sets `TEX[2]` in small page descriptors to tell the
+//! two formats apart, and uses `TEX[1]` as the copy-on-write mark.
+
+use core::fmt;
+use memory_addr::PhysAddr;
+
+use crate::{GenericPTE, MappingFlags, SWAP_SHIFT};
+
+bitflags::bitflags! {
+    /// Attribute fields in the short-descriptor small page descriptors.
+    #[derive(Debug, Clone, Copy)]
+    pub struct PageAttr: u32 {
+        /// Execute-never.
+        const XN =          1 << 0;
+        /// The descriptor is a small page (4K).
+        const SMALL_PAGE =  1 << 1;
+        /// Bufferable bit, bit 0 of the memory attributes index under TEX remap.
+        const B =           1 << 2;
+        /// Cacheable bit, bit 1 of the memory attributes index under TEX remap.
+        const C =           1 << 3;
+        /// Access permission bit 0.
+        const AP0 =         1 << 4;
+        /// Access permission bit 1: accessable at PL0.
+        const AP1 =         1 << 5;
+        /// `TEX[0]`, bit 2 of the memory attributes index under TEX remap.
+        const TEX0 =        1 << 6;
+        /// `TEX[1]`, used to mark copy-on-write descriptors.
+        const TEX1 =        1 << 7;
+        /// `TEX[2]`, used to mark small page descriptors.
+        const TEX2 =        1 << 8;
+        /// Access permission bit 2: read-only.
+        const AP2 =         1 << 9;
+        /// Shareable bit.
+        const S =           1 << 10;
+        /// The not global bit.
+        const NG =          1 << 11;
+    }
+}
+
+bitflags::bitflags! {
+    /// Attribute fields in the short-descriptor section descriptors.
+    #[derive(Debug, Clone, Copy)]
+    pub struct SectionAttr: u32 {
+        /// The Privileged execute-never field (only with the Large Physical
+        /// Address Extension).
+        const PXN =         1 << 0;
+        /// The descriptor is a section (1M) or supersection (16M).
+        const SECTION =     1 << 1;
+        /// Bufferable bit, bit 0 of the memory attributes index under TEX remap.
+        const B =           1 << 2;
+        /// Cacheable bit, bit 1 of the memory attributes index under TEX remap.
+        const C =           1 << 3;
+        /// Execute-never.
+        const XN =          1 << 4;
+        /// The domain field.
+        const DOMAIN =      0b1111 << 5;
+        /// Access permission bit 0.
+        const AP0 =         1 << 10;
+        /// Access permission bit 1: accessable at PL0.
+        const AP1 =         1 << 11;
+        /// `TEX[0]`, bit 2 of the memory attributes index under TEX remap.
+        const TEX0 =        1 << 12;
+        /// `TEX[1]`, used to mark copy-on-write descriptors.
+        const TEX1 =        1 << 13;
+        /// `TEX[2]`, available for software use under TEX remap.
+        const TEX2 =        1 << 14;
+        /// Access permission bit 2: read-only.
+        const AP2 =         1 << 15;
+        /// Shareable bit.
+        const S =           1 << 16;
+        /// The not global bit.
+        const NG =          1 << 17;
+        /// The descriptor is a supersection (16M).
+        const SUPERSECTION = 1 << 18;
+        /// Non-secure bit.
+        const NS =          1 << 19;
+    }
+}
+
+/// Corresponding fields of small page and section descriptors.
+const SECTION_FIELDS: [(PageAttr, SectionAttr); 11] = [
+    (PageAttr::XN, SectionAttr::XN),
+    (PageAttr::SMALL_PAGE, SectionAttr::SECTION),
+    (PageAttr::B, SectionAttr::B),
+    (PageAttr::C, SectionAttr::C),
+    (PageAttr::AP0, SectionAttr::AP0),
+    (PageAttr::AP1, SectionAttr::AP1),
+    (PageAttr::TEX0, SectionAttr::TEX0),
+    (PageAttr::TEX1, SectionAttr::TEX1),
+    (PageAttr::AP2, SectionAttr::AP2),
+    (PageAttr::S, SectionAttr::S),
+    (PageAttr::NG, SectionAttr::NG),
+];
+
+impl From<SectionAttr> for PageAttr {
+    fn from(attr: SectionAttr) -> Self {
+        let mut ret = Self::empty();
+        for (page, section) in SECTION_FIELDS {
+            if attr.contains(section) {
+                ret |= page;
+            }
+        }
+        ret
+    }
+}
+
+impl From<PageAttr> for SectionAttr {
+    fn from(attr: PageAttr) -> Self {
+        let mut ret = Self::empty();
+        for (page, section) in SECTION_FIELDS {
+            if attr.contains(page) {
+                ret |= section;
+            }
+        }
+        ret
+    }
+}
+
+/// The memory attributes index formed by `TEX[0]`, `C` and `B` in the
+/// descriptor, which is used to index into `PRRR` and `NMRR` under TEX remap.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MemAttr {
+    /// Device memory
+    Device = 0,
+    /// Normal memory
+    Normal = 1,
+    /// Normal non-cacheable memory
+    NormalNonCacheable = 2,
+}
+
+impl MemAttr {
+    /// The `PRRR` register should be set to this value to match the memory
+    /// attributes in the descriptors.
+    pub const PRRR_VALUE: u32 = {
+        // TR0 = Device, TR1 = TR2 = Normal
+        let tr = 0b01 | (0b10 << 2) | (0b10 << 4);
+        // DS1, NS1: descriptors with the S bit set are shareable
+        tr | (1 << 17) | (1 << 19) // 0xa_0029
+    };
+
+    /// The `NMRR` register should be set to this value to match the memory
+    /// attributes in the descriptors.
+    pub const NMRR_VALUE: u32 = {
+        // IR1 = OR1 = Write-Back Write-Allocate, IR2 = OR2 = Non-cacheable
+        (0b01 << 2) | (0b01 << 18) // 0x4_0004
+    };
+}
+
+impl PageAttr {
+    /// Constructs a descriptor from the memory index, leaving the other fields
+    /// empty.
+    pub const fn from_mem_attr(idx: MemAttr) -> Self {
+        let mut bits = ((idx as u32 & 0b11) << 2) | ((idx as u32 & 0b100) << 4);
+        if matches!(idx, MemAttr::Normal | MemAttr::NormalNonCacheable) {
+            bits |= Self::S.bits();
+        }
+        Self::from_bits_retain(bits)
+    }
+
+    /// Returns the memory attributes index field.
+    pub const fn mem_attr(&self) -> Option<MemAttr> {
+        let bits = self.bits();
+        let idx = ((bits >> 2) & 0b11) | ((bits >> 4) & 0b100);
+        Some(match idx {
+            0 => MemAttr::Device,
+            1 => MemAttr::Normal,
+            2 => MemAttr::NormalNonCacheable,
+            _ => return None,
+        })
+    }
+}
+
+impl From<PageAttr> for MappingFlags {
+    fn from(attr: PageAttr) -> Self {
+        if !attr.contains(PageAttr::SMALL_PAGE) {
+            return Self::empty();
+        }
+        let mut flags = Self::READ;
+        if !attr.contains(PageAttr::AP2) {
+            flags |= Self::WRITE;
+        }
+        if attr.contains(PageAttr::AP1) {
+            flags |= Self::USER;
+        }
+        if !attr.contains(PageAttr::XN) {
+            flags |= Self::EXECUTE;
+        }
+        match attr.mem_attr() {
+            Some(MemAttr::Device) => flags |= Self::DEVICE,
+            Some(MemAttr::NormalNonCacheable) => flags |= Self::UNCACHED,
+            _ => {}
+        }
+        flags
+    }
+}
+
+impl From<MappingFlags> for PageAttr {
+    fn from(flags: MappingFlags) -> Self {
+        if flags.is_empty() {
+            return Self::empty();
+        }
+        let mut attr = if flags.contains(MappingFlags::DEVICE) {
+            Self::from_mem_attr(MemAttr::Device)
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            Self::from_mem_attr(MemAttr::NormalNonCacheable)
+        } else {
+            Self::from_mem_attr(MemAttr::Normal)
+        };
+        // AP[0] is always set, AP[2:1] select read-only and PL0 access.
+        attr |= Self::SMALL_PAGE | Self::AP0;
+        if !flags.contains(MappingFlags::WRITE) {
+            attr |= Self::AP2;
+        }
+        if flags.contains(MappingFlags::USER) {
+            attr |= Self::AP1;
+        }
+        if !flags.contains(MappingFlags::EXECUTE) {
+            attr |= Self::XN;
+        }
+        attr
+    }
+}
+
+/// A VMSAv7 short-descriptor translation table descriptor.
+///
+/// It can be a first-level page table or section descriptor, or a
+/// second-level small page descriptor. Large pages and supersections are not
+/// used.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct A32PTE(u32);
+
+impl A32PTE {
+    const TYPE_MASK: u32 = 0b11;
+    const TYPE_TABLE: u32 = 0b01;
+    const TABLE_ADDR_MASK: u32 = 0xffff_fc00; // bits 10..32
+    const SECTION_ADDR_MASK: u32 = 0xfff0_0000; // bits 20..32
+    const PAGE_ADDR_MASK: u32 = 0xffff_f000; // bits 12..32
+    /// A swap entry is a fault entry with the bufferable bit set.
+    const SWAP: u32 = PageAttr::B.bits();
+    const SWAP_MASK: u32 = Self::TYPE_MASK | Self::SWAP;
+
+    /// Creates an empty descriptor with all bits set to zero.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    const fn is_table(&self) -> bool {
+        self.0 & Self::TYPE_MASK == Self::TYPE_TABLE
+    }
+
+    const fn is_small_page(&self) -> bool {
+        let mask = PageAttr::SMALL_PAGE.bits() | PageAttr::TEX2.bits();
+        self.0 & mask == mask
+    }
+
+    const fn is_section(&self) -> bool {
+        self.0 & SectionAttr::SECTION.bits() != 0 && !self.is_small_page()
+    }
+
+    const fn addr_mask(&self) -> u32 {
+        if self.is_table() {
+            Self::TABLE_ADDR_MASK
+        } else if self.is_section() {
+            Self::SECTION_ADDR_MASK
+        } else {
+            Self::PAGE_ADDR_MASK
+        }
+    }
+
+    const fn cow_bit(&self) -> u32 {
+        if self.is_section() {
+            SectionAttr::TEX1.bits()
+        } else {
+            PageAttr::TEX1.bits()
+        }
+    }
+
+    /// Returns the attribute fields of a section or small page descriptor, in
+    /// the layout of small page descriptors.
+    fn page_attr(&self) -> PageAttr {
+        if self.is_section() {
+            SectionAttr::from_bits_truncate(self.0).into()
+        } else {
+            PageAttr::from_bits_truncate(self.0)
+        }
+    }
+
+    fn attr_bits(attr: PageAttr, is_huge: bool) -> u32 {
+        if attr.is_empty() {
+            0
+        } else if is_huge {
+            SectionAttr::from(attr).bits()
+        } else {
+            (attr | PageAttr::TEX2).bits()
+        }
+    }
+}
+
+impl GenericPTE for A32PTE {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut pte = Self(Self::attr_bits(flags.into(), is_huge));
+        pte.0 |= paddr.as_usize() as u32 & pte.addr_mask();
+        pte
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(Self::TYPE_TABLE | (paddr.as_usize() as u32 & Self::TABLE_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & self.addr_mask()) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        if self.is_table() {
+            MappingFlags::empty()
+        } else {
+            self.page_attr().into()
+        }
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        let mask = self.addr_mask();
+        self.0 = (self.0 & !mask) | (paddr.as_usize() as u32 & mask)
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let paddr = self.0 & self.addr_mask();
+        let mut attr = PageAttr::from(flags);
+        if !attr.is_empty() {
+            // keep the fields that `MappingFlags` do not control
+            attr |= self.page_attr() & (PageAttr::TEX1 | PageAttr::NG);
+        }
+        *self = Self(Self::attr_bits(attr, is_huge));
+        self.0 |= paddr & self.addr_mask();
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        self.0 & Self::TYPE_MASK != 0
+    }
+    fn is_huge(&self) -> bool {
+        self.is_section()
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.0 & self.cow_bit() != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= self.cow_bit();
+        } else {
+            self.0 &= !self.cow_bit();
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        0 // no bits are left to software
+    }
+    fn set_sw_bits(&mut self, _bits: usize) {}
+
+    fn is_accessed(&self) -> bool {
+        self.is_present() // the access flag is disabled
+    }
+    fn is_dirty(&self) -> bool {
+        self.flags().contains(MappingFlags::WRITE) // no dirty bit
+    }
+    fn clear_accessed(&mut self) {}
+    fn clear_dirty(&mut self) {}
+
+    const SWAP_PAYLOAD_BITS: u32 = 32 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u32) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
+}
+
+impl fmt::Debug for A32PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("A32PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
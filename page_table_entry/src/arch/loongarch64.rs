@@ -4,7 +4,7 @@
 //!
 //! <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#section-multi-level-page-table-structure-supported-by-page-walking>
 
-use crate::{GenericPTE, MappingFlags};
+use crate::{GenericPTE, MappingFlags, SWAP_SHIFT};
 use core::fmt;
 use memory_addr::PhysAddr;
 
@@ -125,6 +125,23 @@ pub struct LA64PTE(u64);
 
 impl LA64PTE {
     const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
+    const COW: u64 = 1 << 9; // ignored by hardware
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = PTEFlags::V.bits()
+        | PTEFlags::D.bits()
+        | PTEFlags::PLVL.bits()
+        | PTEFlags::PLVH.bits()
+        | PTEFlags::MATL.bits()
+        | PTEFlags::MATH.bits()
+        | PTEFlags::GH.bits()
+        | PTEFlags::P.bits()
+        | PTEFlags::W.bits()
+        | PTEFlags::NR.bits()
+        | PTEFlags::NX.bits();
+    const SW_FIELDS: &[(u32, u32)] = &[(10, 2)]; // ignored by hardware, except for COW
+    /// A swap entry is neither valid nor present, and has the dirty bit set.
+    const SWAP: u64 = PTEFlags::D.bits();
+    const SWAP_MASK: u64 = PTEFlags::V.bits() | PTEFlags::P.bits() | PTEFlags::D.bits();
 
     /// Creates an empty descriptor with all bits set to zero.
     pub const fn empty() -> Self {
@@ -157,7 +174,13 @@ impl GenericPTE for LA64PTE {
         if is_huge {
             flags |= PTEFlags::GH;
         }
-        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits();
+        // `D` also enables writes, so it is kept only for entries that stay
+        // writable
+        let old = PTEFlags::from_bits_truncate(self.0);
+        if flags.contains(PTEFlags::W) && old.contains(PTEFlags::W) {
+            flags.set(PTEFlags::D, old.contains(PTEFlags::D));
+        }
+        self.0 = (self.0 & !Self::FLAGS_MASK) | flags.bits();
     }
     fn bits(self) -> usize {
         self.0 as usize
@@ -174,6 +197,44 @@ impl GenericPTE for LA64PTE {
     fn clear(&mut self) {
         self.0 = 0
     }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.is_present() // no accessed bit
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & PTEFlags::D.bits() != 0
+    }
+    fn clear_accessed(&mut self) {}
+    fn clear_dirty(&mut self) {
+        // writes fault until `D` is set again, while `W` keeps the permission
+        self.0 &= !PTEFlags::D.bits();
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
 }
 
 impl fmt::Debug for LA64PTE {
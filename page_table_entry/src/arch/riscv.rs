@@ -3,7 +3,7 @@
 use core::fmt;
 use memory_addr::PhysAddr;
 
-use crate::{GenericPTE, MappingFlags};
+use crate::{GenericPTE, MappingFlags, SWAP_SHIFT};
 
 bitflags::bitflags! {
     /// Page-table entry flags.
@@ -81,6 +81,17 @@ pub struct Rv64PTE(u64);
 
 impl Rv64PTE {
     const PHYS_ADDR_MASK: u64 = (1 << 54) - (1 << 10); // bits 10..54
+    const COW: u64 = 1 << 8; // RSW
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = PTEFlags::V.bits() as u64
+        | PTEFlags::R.bits() as u64
+        | PTEFlags::W.bits() as u64
+        | PTEFlags::X.bits() as u64
+        | PTEFlags::U.bits() as u64;
+    const SW_FIELDS: &[(u32, u32)] = &[(9, 1)]; // RSW, except for COW
+    /// A swap entry is not valid, and has the readable bit set.
+    const SWAP: u64 = PTEFlags::R.bits() as u64;
+    const SWAP_MASK: u64 = (PTEFlags::V.bits() | PTEFlags::R.bits()) as u64;
 
     /// Creates an empty descriptor with all bits set to zero.
     pub const fn empty() -> Self {
@@ -108,9 +119,10 @@ impl GenericPTE for Rv64PTE {
             | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK);
     }
     fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
-        let flags = PTEFlags::from(flags) | PTEFlags::A | PTEFlags::D;
+        // `A` and `D` are kept, so the accessed and dirty state survives
+        let flags = PTEFlags::from(flags);
         debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
-        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits() as u64;
+        self.0 = (self.0 & !Self::FLAGS_MASK) | flags.bits() as u64;
     }
 
     fn bits(self) -> usize {
@@ -128,6 +140,45 @@ impl GenericPTE for Rv64PTE {
     fn clear(&mut self) {
         self.0 = 0
     }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & PTEFlags::A.bits() as u64 != 0
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & PTEFlags::D.bits() as u64 != 0
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !PTEFlags::A.bits() as u64;
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTEFlags::D.bits() as u64;
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
 }
 
 impl fmt::Debug for Rv64PTE {
@@ -139,3 +190,116 @@ impl fmt::Debug for Rv64PTE {
             .finish()
     }
 }
+
+/// Sv32 page table entry for RV32 systems.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Sv32PTE(u32);
+
+impl Sv32PTE {
+    const PHYS_ADDR_MASK: u32 = 0xffff_fc00; // bits 10..32
+    const COW: u32 = 1 << 8; // RSW
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u32 = Rv64PTE::FLAGS_MASK as u32;
+    const SW_FIELDS: &[(u32, u32)] = &[(9, 1)]; // RSW, except for COW
+    /// A swap entry is not valid, and has the readable bit set.
+    const SWAP: u32 = PTEFlags::R.bits() as u32;
+    const SWAP_MASK: u32 = (PTEFlags::V.bits() | PTEFlags::R.bits()) as u32;
+
+    /// Creates an empty descriptor with all bits set to zero.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+}
+
+impl GenericPTE for Sv32PTE {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, _is_huge: bool) -> Self {
+        let flags = PTEFlags::from(flags) | PTEFlags::A | PTEFlags::D;
+        debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
+        Self(flags.bits() as u32 | ((paddr.as_usize() >> 2) as u32 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(PTEFlags::V.bits() as u32 | ((paddr.as_usize() >> 2) as u32 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from(((self.0 & Self::PHYS_ADDR_MASK) as u64 as usize) << 2)
+    }
+    fn flags(&self) -> MappingFlags {
+        PTEFlags::from_bits_truncate(self.0 as usize).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK)
+            | ((paddr.as_usize() >> 2) as u32 & Self::PHYS_ADDR_MASK);
+    }
+    fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
+        // `A` and `D` are kept, so the accessed and dirty state survives
+        let flags = PTEFlags::from(flags);
+        debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
+        self.0 = (self.0 & !Self::FLAGS_MASK) | flags.bits() as u32;
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).contains(PTEFlags::V)
+    }
+    fn is_huge(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).intersects(PTEFlags::R | PTEFlags::X)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0 as u64, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0 as u64, Self::SW_FIELDS, bits) as u32;
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & PTEFlags::A.bits() as u32 != 0
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & PTEFlags::D.bits() as u32 != 0
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !PTEFlags::A.bits() as u32;
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTEFlags::D.bits() as u32;
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 32 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u32) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
+}
+
+impl fmt::Debug for Sv32PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("Sv32PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -1,11 +1,11 @@
-//! x86 page table entries on 64-bit paging.
+//! x86 page table entries on 64-bit and 32-bit paging.
 
 use core::fmt;
 use memory_addr::PhysAddr;
 
 pub use x86_64::structures::paging::page_table::PageTableFlags as PTF;
 
-use crate::{GenericPTE, MappingFlags};
+use crate::{GenericPTE, MappingFlags, SWAP_SHIFT};
 
 impl From<PTF> for MappingFlags {
     fn from(f: PTF) -> Self {
@@ -58,6 +58,18 @@ pub struct X64PTE(u64);
 
 impl X64PTE {
     const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = PTF::PRESENT.bits()
+        | PTF::WRITABLE.bits()
+        | PTF::USER_ACCESSIBLE.bits()
+        | PTF::WRITE_THROUGH.bits()
+        | PTF::NO_CACHE.bits()
+        | PTF::HUGE_PAGE.bits()
+        | PTF::NO_EXECUTE.bits();
+    const SW_FIELDS: &[(u32, u32)] = &[(10, 2), (52, 7)];
+    /// A swap entry is not present, and has the writable bit set.
+    const SWAP: u64 = PTF::WRITABLE.bits();
+    const SWAP_MASK: u64 = PTF::PRESENT.bits() | PTF::WRITABLE.bits(); // AVL, except for COW
 
     /// Creates an empty descriptor with all bits set to zero.
     pub const fn empty() -> Self {
@@ -91,7 +103,7 @@ impl GenericPTE for X64PTE {
         if is_huge {
             flags |= PTF::HUGE_PAGE;
         }
-        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits()
+        self.0 = (self.0 & !Self::FLAGS_MASK) | flags.bits()
     }
 
     fn bits(self) -> usize {
@@ -109,6 +121,45 @@ impl GenericPTE for X64PTE {
     fn clear(&mut self) {
         self.0 = 0
     }
+
+    fn is_cow(&self) -> bool {
+        self.0 & PTF::BIT_9.bits() != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= PTF::BIT_9.bits();
+        } else {
+            self.0 &= !PTF::BIT_9.bits();
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & PTF::ACCESSED.bits() != 0
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & PTF::DIRTY.bits() != 0
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !PTF::ACCESSED.bits();
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTF::DIRTY.bits();
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
 }
 
 impl fmt::Debug for X64PTE {
@@ -120,3 +171,378 @@ impl fmt::Debug for X64PTE {
             .finish()
     }
 }
+
+/// An x86 page table entry for 32-bit (non-PAE) paging.
+///
+/// There is no execute-disable bit in this format, so all present mappings
+/// are executable.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct X86PTE32(u32);
+
+impl X86PTE32 {
+    const PHYS_ADDR_MASK: u32 = 0xffff_f000; // bits 12..32
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u32 = X64PTE::FLAGS_MASK as u32;
+    const SW_FIELDS: &[(u32, u32)] = &[(10, 2)]; // AVL, except for COW
+    /// A swap entry is not present, and has the writable bit set.
+    const SWAP: u32 = PTF::WRITABLE.bits() as u32;
+    const SWAP_MASK: u32 = (PTF::PRESENT.bits() | PTF::WRITABLE.bits()) as u32;
+
+    /// Creates an empty descriptor with all bits set to zero.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    fn flag_bits(flags: MappingFlags, is_huge: bool) -> u32 {
+        let mut flags = PTF::from(flags);
+        if is_huge {
+            flags |= PTF::HUGE_PAGE;
+        }
+        // `NO_EXECUTE` (bit 63) does not exist here and is dropped.
+        flags.bits() as u32
+    }
+}
+
+impl GenericPTE for X86PTE32 {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        Self(Self::flag_bits(flags, is_huge) | (paddr.as_usize() as u32 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        let flags = PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE;
+        Self(flags.bits() as u32 | (paddr.as_usize() as u32 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        PTF::from_bits_truncate(self.0 as u64).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u32 & Self::PHYS_ADDR_MASK)
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        self.0 = (self.0 & !Self::FLAGS_MASK) | Self::flag_bits(flags, is_huge)
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTF::from_bits_truncate(self.0 as u64).contains(PTF::PRESENT)
+    }
+    fn is_huge(&self) -> bool {
+        PTF::from_bits_truncate(self.0 as u64).contains(PTF::HUGE_PAGE)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.0 & PTF::BIT_9.bits() as u32 != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= PTF::BIT_9.bits() as u32;
+        } else {
+            self.0 &= !PTF::BIT_9.bits() as u32;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0 as u64, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0 as u64, Self::SW_FIELDS, bits) as u32;
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & PTF::ACCESSED.bits() as u32 != 0
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & PTF::DIRTY.bits() as u32 != 0
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !PTF::ACCESSED.bits() as u32;
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTF::DIRTY.bits() as u32;
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 32 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u32) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
+}
+
+impl fmt::Debug for X86PTE32 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("X86PTE32");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+bitflags::bitflags! {
+    /// EPT entry flags.
+    #[derive(Debug, Clone, Copy)]
+    pub struct EPTFlags: u64 {
+        /// Read access.
+        const READ =                1 << 0;
+        /// Write access.
+        const WRITE =               1 << 1;
+        /// Execute access, or supervisor-mode execute access if mode-based
+        /// execute control (MBEC) is enabled.
+        const EXECUTE =             1 << 2;
+        /// EPT memory type. Only for terminate pages.
+        const MEM_TYPE_MASK =       0b111 << 3;
+        /// Ignore PAT memory type. Only for terminate pages.
+        const IGNORE_PAT =          1 << 6;
+        /// Specifies that the entry maps a huge frame instead of a page table.
+        /// Only allowed in P2M (1G) and P1M (2M) tables.
+        const HUGE_PAGE =           1 << 7;
+        /// If bit 6 of EPTP is 1, accessed flag for EPT.
+        const ACCESSED =            1 << 8;
+        /// If bit 6 of EPTP is 1, dirty flag for EPT. Only for terminate pages.
+        const DIRTY =               1 << 9;
+        /// Execute access for user-mode linear address, if mode-based execute
+        /// control (MBEC) is enabled.
+        const EXECUTE_FOR_USER =    1 << 10;
+    }
+}
+
+/// EPT memory types, as defined in the Intel SDM.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EPTMemType {
+    /// Uncacheable.
+    Uncached = 0,
+    /// Write-combining.
+    WriteCombining = 1,
+    /// Write-through.
+    WriteThrough = 4,
+    /// Write-protected.
+    WriteProtected = 5,
+    /// Write-back.
+    WriteBack = 6,
+}
+
+impl EPTFlags {
+    /// Constructs flags from the EPT memory type, leaving the other fields
+    /// empty.
+    pub const fn from_mem_type(mem_type: EPTMemType) -> Self {
+        Self::from_bits_retain((mem_type as u64) << 3)
+    }
+
+    /// Returns the EPT memory type field.
+    pub const fn mem_type(&self) -> Option<EPTMemType> {
+        Some(match (self.bits() & Self::MEM_TYPE_MASK.bits()) >> 3 {
+            0 => EPTMemType::Uncached,
+            1 => EPTMemType::WriteCombining,
+            4 => EPTMemType::WriteThrough,
+            5 => EPTMemType::WriteProtected,
+            6 => EPTMemType::WriteBack,
+            _ => return None,
+        })
+    }
+}
+
+impl From<EPTFlags> for MappingFlags {
+    fn from(f: EPTFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(EPTFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(EPTFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        if f.contains(EPTFlags::EXECUTE) {
+            ret |= Self::EXECUTE;
+        }
+        if f.contains(EPTFlags::EXECUTE_FOR_USER) {
+            ret |= Self::EXECUTE | Self::USER;
+        }
+        if let Some(EPTMemType::Uncached) = f.mem_type() {
+            if f.contains(EPTFlags::IGNORE_PAT) {
+                ret |= Self::DEVICE;
+            } else {
+                ret |= Self::UNCACHED;
+            }
+        }
+        ret
+    }
+}
+
+impl From<MappingFlags> for EPTFlags {
+    fn from(f: MappingFlags) -> Self {
+        if f.is_empty() {
+            return Self::empty();
+        }
+        let mut ret = if f.contains(MappingFlags::DEVICE) {
+            // the guest PAT cannot make device memory cacheable
+            Self::from_mem_type(EPTMemType::Uncached) | Self::IGNORE_PAT
+        } else if f.contains(MappingFlags::UNCACHED) {
+            Self::from_mem_type(EPTMemType::Uncached)
+        } else {
+            Self::from_mem_type(EPTMemType::WriteBack)
+        };
+        if f.contains(MappingFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(MappingFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        if f.contains(MappingFlags::EXECUTE) {
+            ret |= Self::EXECUTE;
+            if f.contains(MappingFlags::USER) {
+                ret |= Self::EXECUTE_FOR_USER;
+            }
+        }
+        ret
+    }
+}
+
+/// An x86 Extended Page Table (EPT) entry, which translates guest-physical
+/// addresses to host-physical addresses.
+///
+/// [`MappingFlags::USER`] only has effect together with
+/// [`MappingFlags::EXECUTE`], and grants user-mode execute access under
+/// mode-based execute control (MBEC). Device memory is mapped uncacheable
+/// with the guest PAT memory type ignored, while uncached and normal memory
+/// are combined with the guest PAT memory type.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct EPTEntry(u64);
+
+impl EPTEntry {
+    const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+    const COW: u64 = 1 << 11; // ignored by hardware
+    /// The bits controlled by [`MappingFlags`].
+    const FLAGS_MASK: u64 = EPTFlags::READ.bits()
+        | EPTFlags::WRITE.bits()
+        | EPTFlags::EXECUTE.bits()
+        | EPTFlags::MEM_TYPE_MASK.bits()
+        | EPTFlags::IGNORE_PAT.bits()
+        | EPTFlags::HUGE_PAGE.bits()
+        | EPTFlags::EXECUTE_FOR_USER.bits();
+    const SW_FIELDS: &[(u32, u32)] = &[(52, 5)]; // ignored by hardware
+    /// A swap entry has no access rights, and a memory type of 1.
+    const SWAP: u64 = 1 << 3;
+    const SWAP_MASK: u64 = EPTFlags::READ.bits()
+        | EPTFlags::WRITE.bits()
+        | EPTFlags::EXECUTE.bits()
+        | EPTFlags::EXECUTE_FOR_USER.bits()
+        | Self::SWAP;
+
+    /// Creates an empty descriptor with all bits set to zero.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+}
+
+impl GenericPTE for EPTEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut flags = EPTFlags::from(flags);
+        if is_huge {
+            flags |= EPTFlags::HUGE_PAGE;
+        }
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        let flags =
+            EPTFlags::READ | EPTFlags::WRITE | EPTFlags::EXECUTE | EPTFlags::EXECUTE_FOR_USER;
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        EPTFlags::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK)
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut flags = EPTFlags::from(flags);
+        if is_huge {
+            flags |= EPTFlags::HUGE_PAGE;
+        }
+        self.0 = (self.0 & !Self::FLAGS_MASK) | flags.bits()
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).intersects(
+            EPTFlags::READ | EPTFlags::WRITE | EPTFlags::EXECUTE | EPTFlags::EXECUTE_FOR_USER,
+        )
+    }
+    fn is_huge(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::HUGE_PAGE)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_cow(&self) -> bool {
+        self.0 & Self::COW != 0
+    }
+    fn set_cow(&mut self, cow: bool) {
+        if cow {
+            self.0 |= Self::COW;
+        } else {
+            self.0 &= !Self::COW;
+        }
+    }
+    fn sw_bits(&self) -> usize {
+        crate::pack_bits(self.0, Self::SW_FIELDS)
+    }
+    fn set_sw_bits(&mut self, bits: usize) {
+        self.0 = crate::unpack_bits(self.0, Self::SW_FIELDS, bits);
+    }
+
+    fn is_accessed(&self) -> bool {
+        self.0 & EPTFlags::ACCESSED.bits() != 0
+    }
+    fn is_dirty(&self) -> bool {
+        self.0 & EPTFlags::DIRTY.bits() != 0
+    }
+    fn clear_accessed(&mut self) {
+        self.0 &= !EPTFlags::ACCESSED.bits();
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !EPTFlags::DIRTY.bits();
+    }
+
+    const SWAP_PAYLOAD_BITS: u32 = 64 - SWAP_SHIFT;
+
+    fn new_swap(payload: usize) -> Self {
+        Self(Self::SWAP | ((payload as u64) << SWAP_SHIFT))
+    }
+    fn swap_payload(&self) -> Option<usize> {
+        (self.0 & Self::SWAP_MASK == Self::SWAP).then_some((self.0 >> SWAP_SHIFT) as usize)
+    }
+}
+
+impl fmt::Debug for EPTEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("EPTEntry");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .field("mem_type", &EPTFlags::from_bits_truncate(self.0).mem_type())
+            .finish()
+    }
+}
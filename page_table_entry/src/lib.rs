@@ -1,5 +1,5 @@
 #![cfg_attr(not(test), no_std)]
-#![cfg_attr(doc, feature(doc_auto_cfg))]
+#![cfg_attr(doc, feature(doc_cfg))]
 #![doc = include_str!("../README.md")]
 
 mod arch;
@@ -52,6 +52,12 @@ pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
     /// Set mapped physical address of the entry.
     fn set_paddr(&mut self, paddr: PhysAddr);
     /// Set flags of the entry.
+    ///
+    /// Only the bits that [`MappingFlags`] control are changed. Others, such
+    /// as the global bit and the software bits, are kept. So are the accessed
+    /// and dirty state, except where the architecture tracks the dirty state
+    /// with the write permission: an entry made writable is then dirty, and
+    /// one made read-only is clean.
     fn set_flags(&mut self, flags: MappingFlags, is_huge: bool);
 
     /// Returns the raw bits of this entry.
@@ -65,4 +71,115 @@ pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
     fn is_huge(&self) -> bool;
     /// Set this entry to zero.
     fn clear(&mut self);
+
+    /// Returns whether this entry is marked as copy-on-write.
+    ///
+    /// The mark is kept in a bit that the hardware leaves to software. The
+    /// default implementation has no such bit and always returns `false`.
+    fn is_cow(&self) -> bool {
+        false
+    }
+    /// Marks or unmarks this entry as copy-on-write.
+    ///
+    /// Only the mark is changed, not the access permissions. The default
+    /// implementation does nothing, so entries using it cannot be shared
+    /// copy-on-write.
+    fn set_cow(&mut self, _cow: bool) {}
+
+    /// Returns the bits of this entry that the hardware leaves to software,
+    /// packed into the low bits.
+    ///
+    /// How many bits are available depends on the architecture. The bit used
+    /// by [`is_cow`](Self::is_cow) is not included. The default
+    /// implementation has no software bits and returns 0.
+    fn sw_bits(&self) -> usize {
+        0
+    }
+    /// Sets the software bits of this entry, packed as returned by
+    /// [`sw_bits`](Self::sw_bits).
+    ///
+    /// The bits beyond the available ones are ignored.
+    fn set_sw_bits(&mut self, _bits: usize) {}
+
+    /// Returns whether the mapped memory has been accessed since the accessed
+    /// state was last cleared.
+    ///
+    /// Without an accessed bit in the architecture, it is always `true` for
+    /// present entries, which is also the default implementation.
+    fn is_accessed(&self) -> bool {
+        self.is_present()
+    }
+    /// Returns whether the mapped memory has been written since the dirty
+    /// state was last cleared.
+    ///
+    /// Without a dirty bit in the architecture, it is always `true` for
+    /// present writable entries, which is also the default implementation.
+    fn is_dirty(&self) -> bool {
+        self.is_present() && self.flags().contains(MappingFlags::WRITE)
+    }
+    /// Clears the accessed state of this entry.
+    ///
+    /// Unless the hardware updates the accessed state, the next access will
+    /// fault. The default implementation does nothing.
+    fn clear_accessed(&mut self) {}
+    /// Clears the dirty state of this entry.
+    ///
+    /// Unless the hardware updates the dirty state, the next write will
+    /// fault. The default implementation does nothing.
+    fn clear_dirty(&mut self) {}
+
+    /// The number of bits of the payload that a swap entry can hold.
+    ///
+    /// It is 0 by default, meaning that swap entries are not supported.
+    const SWAP_PAYLOAD_BITS: u32 = 0;
+    /// Creates a non-present entry that holds `payload` for software, such as
+    /// a swap slot or a marker for a page not loaded yet.
+    ///
+    /// The entry is neither unused nor present, and the hardware ignores it.
+    /// The bits of `payload` beyond
+    /// [`SWAP_PAYLOAD_BITS`](Self::SWAP_PAYLOAD_BITS) are lost.
+    ///
+    /// # Panics
+    ///
+    /// The default implementation, for entries without swap support, always
+    /// panics.
+    fn new_swap(_payload: usize) -> Self {
+        unimplemented!("swap entries are not supported")
+    }
+    /// Returns the payload if this entry is created by
+    /// [`new_swap`](Self::new_swap), or [`None`] otherwise.
+    ///
+    /// The default implementation always returns [`None`].
+    fn swap_payload(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The first bit of the payload in swap entries, which takes the place of the
+/// physical address.
+const SWAP_SHIFT: u32 = 12;
+
+/// Gathers the bit fields of `bits`, given as `(shift, width)` pairs, into
+/// the low bits of the result.
+fn pack_bits(bits: u64, fields: &[(u32, u32)]) -> usize {
+    let mut ret = 0;
+    let mut pos = 0;
+    for &(shift, width) in fields {
+        ret |= ((bits >> shift) & ((1 << width) - 1)) << pos;
+        pos += width;
+    }
+    ret as usize
+}
+
+/// Scatters the low bits of `value` into the bit fields of `bits`, given as
+/// `(shift, width)` pairs, and returns the result.
+fn unpack_bits(bits: u64, fields: &[(u32, u32)], value: usize) -> u64 {
+    let mut ret = bits;
+    let mut pos = 0;
+    for &(shift, width) in fields {
+        let mask = ((1 << width) - 1) << shift;
+        ret = (ret & !mask) | (((value as u64 >> pos) << shift) & mask);
+        pos += width;
+    }
+    ret
 }
@@ -1,11 +1,253 @@
 //! AArch64 specific page table structures.
 
-use core::arch::asm;
+use core::marker::PhantomData;
 
-use memory_addr::VirtAddr;
-use page_table_entry::aarch64::A64PTE;
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use page_table_entry::aarch64::{A64PTE, A64S2PTE};
 
-use crate::{PageTable64, PageTable64Mut, PagingMetaData};
+use crate::{PageTable64, PageTable64Mut, PagingMetaData, SplitRootsMetaData};
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn a64_flush_tlb(vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // TLB Invalidate by VA, All ASID, EL1, Inner Shareable
+            const VA_MASK: usize = (1 << 44) - 1; // VA[55:12] => bits[43:0]
+            asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) ((vaddr.as_usize() >> 12) & VA_MASK))
+        } else {
+            // TLB Invalidate by VMID, All at stage 1, EL1
+            asm!("tlbi vmalle1; dsb sy; isb")
+        }
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_flush_tlb(_vaddr: Option<VirtAddr>) {}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn a64_flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    let asid = (asid as usize) << 48; // ASID => bits[63:48]
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // TLB Invalidate by VA, EL1, Inner Shareable
+            const VA_MASK: usize = (1 << 44) - 1; // VA[55:12] => bits[43:0]
+            asm!("tlbi vae1is, {}; dsb sy; isb", in(reg) asid | ((vaddr.as_usize() >> 12) & VA_MASK))
+        } else {
+            // TLB Invalidate by ASID, EL1, Inner Shareable
+            asm!("tlbi aside1is, {}; dsb sy; isb", in(reg) asid)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_flush_tlb_asid(_asid: u16, _vaddr: Option<VirtAddr>) {}
+
+/// Whether the CPU implements FEAT_TLBIRANGE, detected on the first range
+/// flush: 0 if it is not detected yet, 1 if not, and 2 if it does.
+#[cfg(target_arch = "aarch64")]
+static TLBI_RANGE: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+#[cfg(target_arch = "aarch64")]
+fn a64_has_tlbi_range() -> bool {
+    use core::sync::atomic::Ordering;
+    match TLBI_RANGE.load(Ordering::Relaxed) {
+        0 => {
+            let isar0: usize;
+            unsafe { core::arch::asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0) };
+            // TLB => bits[59:56], 0b0010 with the TLBI range instructions
+            let has_range = ((isar0 >> 56) & 0xf) >= 2;
+            TLBI_RANGE.store(if has_range { 2 } else { 1 }, Ordering::Relaxed);
+            has_range
+        }
+        state => state == 2,
+    }
+}
+
+/// Flushes the TLB entries of the range at stage 1, of the ASID `asid` if it
+/// is given, and waits for the invalidations once at the end.
+///
+/// With FEAT_TLBIRANGE, the range is invalidated by `TLBI RVAE1IS` (or
+/// `RVAAE1IS`) in a few operations. Otherwise, the pages are invalidated by
+/// `TLBI VAE1IS` (or `VAAE1IS`) one by one, or the whole TLB if there are
+/// more than `FLUSH_RANGE_MAX_PAGES`.
+#[cfg(target_arch = "aarch64")]
+fn a64_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    start: VirtAddr,
+    end: VirtAddr,
+    stride: usize,
+    asid: Option<u16>,
+) {
+    use core::arch::asm;
+    const VA_MASK: usize = (1 << 44) - 1; // VA[55:12] => bits[43:0]
+    const BADDR_MASK: usize = (1 << 37) - 1; // BaseADDR => bits[36:0]
+    // a range operation covers (NUM + 1) << (5 * SCALE + 1) base pages, with
+    // NUM and SCALE up to 31 and 3
+    const RANGE_MAX_PAGES: usize = 1 << 21;
+
+    let start = start.as_usize();
+    let size = end.as_usize().wrapping_sub(start);
+    let pages = size >> M::PAGE_SHIFT;
+    let has_range = a64_has_tlbi_range();
+    if size / stride > M::FLUSH_RANGE_MAX_PAGES || (has_range && pages >= RANGE_MAX_PAGES) {
+        match asid {
+            Some(asid) => a64_flush_tlb_asid(asid, None),
+            None => a64_flush_tlb(None),
+        }
+        return;
+    }
+    let asid_bits = asid.map_or(0, |asid| (asid as usize) << 48); // ASID => bits[63:48]
+
+    unsafe {
+        // make the changed entries visible to the table walkers first
+        asm!("dsb ishst");
+        if has_range {
+            // TG => bits[47:46]: 0b01 for 4K, 0b10 for 16K, and 0b11 for 64K
+            let tg = match M::PAGE_SHIFT {
+                12 => 0b01,
+                14 => 0b10,
+                _ => 0b11,
+            };
+            let mut base = start >> M::PAGE_SHIFT;
+            let mut left = pages;
+            for scale in 0..4 {
+                // SCALE => bits[45:44], NUM => bits[43:39], TTL => bits[38:37]
+                let num = (left >> (5 * scale + 1)) & 0x1f;
+                if num > 0 {
+                    let count = num << (5 * scale + 1);
+                    let arg = asid_bits
+                        | (tg << 46)
+                        | (scale << 44)
+                        | ((num - 1) << 39)
+                        | (base & BADDR_MASK);
+                    if asid.is_some() {
+                        // TLBI RVAE1IS
+                        asm!("sys #0, c8, c2, #1, {}", in(reg) arg);
+                    } else {
+                        // TLBI RVAAE1IS
+                        asm!("sys #0, c8, c2, #3, {}", in(reg) arg);
+                    }
+                    base += count;
+                    left -= count;
+                }
+            }
+            if left > 0 {
+                // an odd page is left, since the ranges are of even pages
+                let arg = asid_bits | ((base << M::PAGE_SHIFT >> 12) & VA_MASK);
+                if asid.is_some() {
+                    asm!("tlbi vae1is, {}", in(reg) arg);
+                } else {
+                    asm!("tlbi vaae1is, {}", in(reg) arg);
+                }
+            }
+        } else {
+            for i in 0..size / stride {
+                let vaddr = start.wrapping_add(i * stride);
+                let arg = asid_bits | ((vaddr >> 12) & VA_MASK);
+                if asid.is_some() {
+                    asm!("tlbi vae1is, {}", in(reg) arg);
+                } else {
+                    asm!("tlbi vaae1is, {}", in(reg) arg);
+                }
+            }
+        }
+        asm!("dsb ish; isb");
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    _start: VirtAddr,
+    _end: VirtAddr,
+    _stride: usize,
+    _asid: Option<u16>,
+) {
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn a64_s2_flush_tlb(ipa: Option<usize>) {
+    use core::arch::asm;
+    unsafe {
+        if let Some(ipa) = ipa {
+            // TLB Invalidate by IPA, Stage 2, EL1, Inner Shareable. Cached
+            // stage 1 translations may still combine the old stage 2 entry, so
+            // they are invalidated for the current VMID as well.
+            const IPA_MASK: usize = (1 << 36) - 1; // IPA[47:12] => bits[35:0]
+            asm!(
+                "tlbi ipas2e1is, {}; dsb ish; tlbi vmalle1is; dsb ish; isb",
+                in(reg) (ipa >> 12) & IPA_MASK
+            )
+        } else {
+            // TLB Invalidate by VMID, All at Stage 1 and 2, EL1, Inner Shareable
+            asm!("tlbi vmalls12e1is; dsb ish; isb")
+        }
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_s2_flush_tlb(_ipa: Option<usize>) {}
+
+/// The table base address in `TTBR0_EL1` and `VTTBR_EL2`.
+#[cfg(target_arch = "aarch64")]
+const A64_BADDR_MASK: usize = 0x0000_ffff_ffff_fffe; // BADDR => bits[47:1]
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+unsafe fn a64_activate(root: PhysAddr, asid: Option<u16>) {
+    let asid = (asid.unwrap_or(0) as usize) << 48; // ASID => bits[63:48]
+    unsafe { core::arch::asm!("msr ttbr0_el1, {}; isb", in(reg) asid | root.as_usize()) }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+unsafe fn a64_activate(_root: PhysAddr, _asid: Option<u16>) {}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn a64_read_root() -> Option<PhysAddr> {
+    let ttbr: usize;
+    unsafe { core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr) };
+    Some(PhysAddr::from(ttbr & A64_BADDR_MASK))
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_read_root() -> Option<PhysAddr> {
+    None
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+unsafe fn a64_s2_activate(root: PhysAddr, vmid: Option<u16>) {
+    let vmid = (vmid.unwrap_or(0) as usize) << 48; // VMID => bits[63:48]
+    unsafe { core::arch::asm!("msr vttbr_el2, {}; isb", in(reg) vmid | root.as_usize()) }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+unsafe fn a64_s2_activate(_root: PhysAddr, _vmid: Option<u16>) {}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn a64_s2_read_root() -> Option<PhysAddr> {
+    let vttbr: usize;
+    unsafe { core::arch::asm!("mrs {}, vttbr_el2", out(reg) vttbr) };
+    Some(PhysAddr::from(vttbr & A64_BADDR_MASK))
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[inline]
+fn a64_s2_read_root() -> Option<PhysAddr> {
+    None
+}
 
 /// Metadata of AArch64 page tables.
 pub struct A64PagingMetaData;
@@ -14,6 +256,11 @@ impl PagingMetaData for A64PagingMetaData {
     const LEVELS: usize = 4;
     const PA_MAX_BITS: usize = 48;
     const VA_MAX_BITS: usize = 48;
+    const BREAK_BEFORE_MAKE: bool = true;
+    // up to the pages mapped by a last level table
+    const FLUSH_RANGE_MAX_PAGES: usize = 1 << Self::LEVEL_INDEX_BITS;
+    // TTBR0_EL1 and TTBR1_EL1
+    const SPLIT_ROOTS: bool = true;
     type VirtAddr = VirtAddr;
 
     fn vaddr_is_valid(vaddr: usize) -> bool {
@@ -23,19 +270,200 @@ impl PagingMetaData for A64PagingMetaData {
 
     #[inline]
     fn flush_tlb(vaddr: Option<VirtAddr>) {
-        unsafe {
-            if let Some(vaddr) = vaddr {
-                // TLB Invalidate by VA, All ASID, EL1, Inner Shareable
-                const VA_MASK: usize = (1 << 44) - 1; // VA[55:12] => bits[43:0]
-                asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) ((vaddr.as_usize() >> 12) & VA_MASK))
-            } else {
-                // TLB Invalidate by VMID, All at stage 1, EL1
-                asm!("tlbi vmalle1; dsb sy; isb")
-            }
-        }
+        a64_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        a64_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        a64_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes `TTBR0_EL1`, so the page table translates the lower half of the
+    /// virtual address space. `TCR_EL1.A1` must be 0 to use its ASID.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { a64_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a64_read_root()
+    }
+}
+
+impl SplitRootsMetaData for A64PagingMetaData {}
+
+/// Metadata of AArch64 page tables with the 16K translation granule.
+///
+/// There are 4 levels, and the level 0 table only has 2 entries to cover the
+/// 48-bit virtual address space. Blocks are only supported at level 2 (32M).
+pub struct A64Granule16KMetaData;
+
+impl PagingMetaData for A64Granule16KMetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 48;
+    const VA_MAX_BITS: usize = 48;
+    const PAGE_SHIFT: usize = 14;
+    const LEVEL_INDEX_BITS: usize = 11;
+    const ROOT_INDEX_BITS: usize = 1;
+    const BREAK_BEFORE_MAKE: bool = true;
+    const FLUSH_RANGE_MAX_PAGES: usize = 1 << Self::LEVEL_INDEX_BITS;
+    const SPLIT_ROOTS: bool = true;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        A64PagingMetaData::vaddr_is_valid(vaddr)
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        a64_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        a64_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        a64_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { a64_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a64_read_root()
+    }
+}
+
+impl SplitRootsMetaData for A64Granule16KMetaData {}
+
+/// Metadata of AArch64 page tables with the 64K translation granule.
+///
+/// There are 3 levels, and the level 1 table only has 64 entries to cover the
+/// 48-bit virtual address space. Blocks are only supported at level 2 (512M).
+pub struct A64Granule64KMetaData;
+
+impl PagingMetaData for A64Granule64KMetaData {
+    const LEVELS: usize = 3;
+    const PA_MAX_BITS: usize = 48;
+    const VA_MAX_BITS: usize = 48;
+    const PAGE_SHIFT: usize = 16;
+    const LEVEL_INDEX_BITS: usize = 13;
+    const ROOT_INDEX_BITS: usize = 6;
+    const BREAK_BEFORE_MAKE: bool = true;
+    const FLUSH_RANGE_MAX_PAGES: usize = 1 << Self::LEVEL_INDEX_BITS;
+    const SPLIT_ROOTS: bool = true;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        A64PagingMetaData::vaddr_is_valid(vaddr)
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        a64_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        a64_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        a64_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { a64_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a64_read_root()
+    }
+}
+
+impl SplitRootsMetaData for A64Granule64KMetaData {}
+
+/// Metadata of AArch64 stage 2 translation tables.
+///
+/// The table translates `IPA_BITS`-bit intermediate physical addresses (IPA)
+/// of type `IPA` with the 4K granule (`VTCR_EL2.T0SZ = 64 - IPA_BITS`).
+/// `IPA_BITS` can be 32 to 48.
+///
+/// The lookup starts at the level that needs the fewest levels, concatenating
+/// up to 16 tables at the initial level (`VTCR_EL2.SL0` must be set
+/// accordingly). For example, a 48-bit IPA space starts at level 0 with a
+/// single table, while a 40-bit IPA space starts at level 1 with 2
+/// concatenated tables. The concatenated root is allocated as contiguous
+/// frames aligned to its size.
+///
+/// The TLB is flushed for the VMID in `VTTBR_EL2`, so this must be used at
+/// EL2.
+pub struct A64Stage2MetaData<IPA: MemoryAddr = VirtAddr, const IPA_BITS: usize = 48>(
+    PhantomData<fn() -> IPA>,
+);
+
+impl<IPA: MemoryAddr, const IPA_BITS: usize> PagingMetaData for A64Stage2MetaData<IPA, IPA_BITS> {
+    const LEVELS: usize = {
+        assert!(IPA_BITS >= 32 && IPA_BITS <= 48, "unsupported IPA size");
+        // the root level can resolve up to 9 + 4 bits with concatenation
+        (IPA_BITS - 12 - 5) / 9 + 1
+    };
+    const PA_MAX_BITS: usize = 48;
+    const VA_MAX_BITS: usize = IPA_BITS;
+    const ROOT_INDEX_BITS: usize = IPA_BITS - 12 - (Self::LEVELS - 1) * 9;
+    const BREAK_BEFORE_MAKE: bool = true;
+    type VirtAddr = IPA;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        // IPAs are not sign-extended
+        vaddr >> Self::VA_MAX_BITS == 0
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<IPA>) {
+        a64_s2_flush_tlb(vaddr.map(Into::into));
+    }
+
+    /// Writes `VTTBR_EL2`, with the VMID `asid`.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { a64_s2_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a64_s2_read_root()
     }
 }
 
 /// AArch64 VMSAv8-64 translation table.
 pub type A64PageTable<H> = PageTable64<A64PagingMetaData, A64PTE, H>;
-pub type A64PageTableMut<H> = PageTable64Mut<'_, A64PagingMetaData, A64PTE, H>;
+pub type A64PageTableMut<'a, H> = PageTable64Mut<'a, A64PagingMetaData, A64PTE, H>;
+
+/// AArch64 VMSAv8-64 translation table with the 16K translation granule.
+pub type A64Granule16KPageTable<H> = PageTable64<A64Granule16KMetaData, A64PTE, H>;
+pub type A64Granule16KPageTableMut<'a, H> = PageTable64Mut<'a, A64Granule16KMetaData, A64PTE, H>;
+
+/// AArch64 VMSAv8-64 translation table with the 64K translation granule.
+pub type A64Granule64KPageTable<H> = PageTable64<A64Granule64KMetaData, A64PTE, H>;
+pub type A64Granule64KPageTableMut<'a, H> = PageTable64Mut<'a, A64Granule64KMetaData, A64PTE, H>;
+
+/// AArch64 VMSAv8-64 stage 2 translation table.
+pub type A64Stage2PageTable<H, IPA = VirtAddr> = PageTable64<A64Stage2MetaData<IPA>, A64S2PTE, H>;
+pub type A64Stage2PageTableMut<'a, H, IPA = VirtAddr> =
+    PageTable64Mut<'a, A64Stage2MetaData<IPA>, A64S2PTE, H>;
@@ -0,0 +1,243 @@
+//! ARMv7-A specific page table structures.
+
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_entry::arm::A32PTE;
+
+use crate::{PageTable32, PageTable32Mut, PagingMetaData};
+
+#[cfg(target_arch = "arm")]
+#[inline]
+fn a32_flush_tlb(vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // TLBIMVAAIS: TLB Invalidate by MVA, All ASID, Inner Shareable
+            asm!("mcr p15, 0, {}, c8, c3, 3; dsb; isb", in(reg) vaddr.as_usize() & !0xfff)
+        } else {
+            // TLBIALLIS: TLB Invalidate All, Inner Shareable
+            asm!("mcr p15, 0, {}, c8, c3, 0; dsb; isb", in(reg) 0)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+fn a32_flush_tlb(_vaddr: Option<VirtAddr>) {}
+
+#[cfg(target_arch = "arm")]
+#[inline]
+fn a32_flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    let asid = asid as usize & 0xff;
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // TLBIMVAIS: TLB Invalidate by MVA and ASID, Inner Shareable
+            asm!("mcr p15, 0, {}, c8, c3, 1; dsb; isb", in(reg) (vaddr.as_usize() & !0xfff) | asid)
+        } else {
+            // TLBIASIDIS: TLB Invalidate by ASID, Inner Shareable
+            asm!("mcr p15, 0, {}, c8, c3, 2; dsb; isb", in(reg) asid)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+fn a32_flush_tlb_asid(_asid: u16, _vaddr: Option<VirtAddr>) {}
+
+/// Table walks are Inner Shareable, and Inner and Outer Write-Back
+/// Write-Allocate Cacheable: IRGN = 0b01, S = 1, RGN = 0b01, NOS = 1.
+#[cfg(target_arch = "arm")]
+const TTBR_WALK_ATTRS: usize = (1 << 6) | (1 << 1) | (1 << 3) | (1 << 5);
+
+#[cfg(target_arch = "arm")]
+#[inline]
+unsafe fn a32_activate(root: PhysAddr, asid: Option<u16>, has_ttbr1: bool) {
+    use core::arch::asm;
+    let asid = asid.unwrap_or(0) as usize & 0xff;
+    let ttbr = root.as_usize() | TTBR_WALK_ATTRS;
+    unsafe {
+        if has_ttbr1 {
+            // While the ASID and TTBR0 are changed one after the other, walks
+            // with TTBR0 are disabled by TTBCR.PD0 (bit 4), so the entries of
+            // the old table are never cached with the new ASID. Then CONTEXTIDR
+            // is written, with the PROCID field left zero, and TTBR0.
+            asm!(
+                "mrc p15, 0, {ttbcr}, c2, c0, 2",
+                "orr {ttbcr}, {ttbcr}, #0x10",
+                "mcr p15, 0, {ttbcr}, c2, c0, 2",
+                "isb",
+                "mcr p15, 0, {asid}, c13, c0, 1",
+                "isb",
+                "mcr p15, 0, {ttbr}, c2, c0, 0",
+                "isb",
+                "bic {ttbcr}, {ttbcr}, #0x10",
+                "mcr p15, 0, {ttbcr}, c2, c0, 2",
+                "isb",
+                ttbcr = out(reg) _,
+                asid = in(reg) asid,
+                ttbr = in(reg) ttbr,
+            );
+        } else {
+            // Without a TTBR1 region, TTBR0 must stay enabled, so it is changed
+            // under the reserved ASID 0 instead.
+            asm!(
+                "mcr p15, 0, {zero}, c13, c0, 1",
+                "isb",
+                "mcr p15, 0, {ttbr}, c2, c0, 0",
+                "isb",
+                "mcr p15, 0, {asid}, c13, c0, 1",
+                "isb",
+                zero = in(reg) 0,
+                asid = in(reg) asid,
+                ttbr = in(reg) ttbr,
+            );
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+unsafe fn a32_activate(_root: PhysAddr, _asid: Option<u16>, _has_ttbr1: bool) {}
+
+#[cfg(target_arch = "arm")]
+#[inline]
+unsafe fn a32_activate_ttbr1(root: PhysAddr) {
+    unsafe {
+        core::arch::asm!(
+            "mcr p15, 0, {}, c2, c0, 1; isb",
+            in(reg) root.as_usize() | TTBR_WALK_ATTRS,
+        )
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+unsafe fn a32_activate_ttbr1(_root: PhysAddr) {}
+
+#[cfg(target_arch = "arm")]
+#[inline]
+fn a32_read_root(ttbr1: bool) -> Option<PhysAddr> {
+    let ttbr: usize;
+    unsafe {
+        if ttbr1 {
+            core::arch::asm!("mrc p15, 0, {}, c2, c0, 1", out(reg) ttbr)
+        } else {
+            core::arch::asm!("mrc p15, 0, {}, c2, c0, 0", out(reg) ttbr)
+        }
+    }
+    Some(PhysAddr::from(ttbr & !0x7f))
+}
+
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+fn a32_read_root(_ttbr1: bool) -> Option<PhysAddr> {
+    None
+}
+
+/// Metadata of ARMv7-A short-descriptor translation tables that are walked
+/// with `TTBR0`, where `N` is the value of `TTBCR.N`, 2 by default.
+///
+/// The table translates the lower `4G >> N` of the virtual address space, so
+/// the first level table has `4096 >> N` entries, and takes 16K when `N` is 0.
+/// The rest, if any, is translated by the table of [`A32Ttbr1MetaData`].
+/// Second level tables only have 256 entries (1K), but still occupy a full
+/// frame each. Sections (1M) are supported at the first level.
+///
+/// `N` must be at most 7, the largest value of `TTBCR.N`. When it is 0,
+/// [`PageTable64Mut::copy_from`](crate::PageTable64Mut::copy_from) only
+/// borrows the first level entries of the lowest 1G.
+pub struct A32PagingMetaData<const N: usize = 2>;
+
+impl<const N: usize> PagingMetaData for A32PagingMetaData<N> {
+    const LEVELS: usize = 2;
+    const PA_MAX_BITS: usize = 32;
+    const VA_MAX_BITS: usize = 32 - N;
+    const LEVEL_INDEX_BITS: usize = 8;
+    const ROOT_INDEX_BITS: usize = 12 - N;
+    const BREAK_BEFORE_MAKE: bool = true;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        vaddr.checked_shr(Self::VA_MAX_BITS as u32).unwrap_or(0) == 0
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        a32_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        a32_flush_tlb_asid(asid, vaddr);
+    }
+
+    /// Writes `TTBR0`, and the ASID `asid` to `CONTEXTIDR`.
+    ///
+    /// When `N` is not 0, translations with `TTBR0` fault while they are
+    /// changed, so the code and data in use must be in the `TTBR1` region.
+    /// Otherwise, `TTBR0` is changed under ASID 0, which must be reserved.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { a32_activate(root, asid, N != 0) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a32_read_root(false)
+    }
+}
+
+/// Metadata of ARMv7-A short-descriptor translation tables that are walked
+/// with `TTBR1`, where `N` is the value of `TTBCR.N`, 2 by default.
+///
+/// The table translates the upper part of the virtual address space, starting
+/// at `4G >> N`, that [`A32PagingMetaData`] leaves. It is indexed like a table
+/// for the whole 4G, so the first level table always has 4096 entries (16K),
+/// of which those below `4G >> N` are unused. There is no such region when `N`
+/// is 0, so no address is valid then.
+///
+/// Mappings in this region are normally global, so its table is shared by all
+/// address spaces and not tagged with an ASID.
+pub struct A32Ttbr1MetaData<const N: usize = 2>;
+
+impl<const N: usize> PagingMetaData for A32Ttbr1MetaData<N> {
+    const LEVELS: usize = 2;
+    const PA_MAX_BITS: usize = 32;
+    const VA_MAX_BITS: usize = 32;
+    const LEVEL_INDEX_BITS: usize = 8;
+    const ROOT_INDEX_BITS: usize = 12;
+    const BREAK_BEFORE_MAKE: bool = true;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        N != 0
+            && vaddr.checked_shr(32).unwrap_or(0) == 0
+            && vaddr.checked_shr(32 - N as u32).unwrap_or(0) != 0
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        a32_flush_tlb(vaddr);
+    }
+
+    /// Writes `TTBR1`. The ASID `asid` is ignored.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, _asid: Option<u16>) {
+        unsafe { a32_activate_ttbr1(root) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        a32_read_root(true)
+    }
+}
+
+/// ARMv7-A VMSAv7 short-descriptor translation table, walked with `TTBR0`
+/// when `TTBCR.N` is 2.
+pub type A32PageTable<H> = PageTable32<A32PagingMetaData, A32PTE, H>;
+pub type A32PageTableMut<'a, H> = PageTable32Mut<'a, A32PagingMetaData, A32PTE, H>;
+
+/// ARMv7-A VMSAv7 short-descriptor translation table, walked with `TTBR1`
+/// when `TTBCR.N` is 2.
+pub type A32Ttbr1PageTable<H> = PageTable32<A32Ttbr1MetaData, A32PTE, H>;
+pub type A32Ttbr1PageTableMut<'a, H> = PageTable32Mut<'a, A32Ttbr1MetaData, A32PTE, H>;
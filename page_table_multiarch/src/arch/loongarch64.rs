@@ -1,11 +1,101 @@
 //! LoongArch64 specific page table structures.
 
-use core::arch::asm;
-
-use memory_addr::VirtAddr;
+use memory_addr::{PhysAddr, VirtAddr};
 use page_table_entry::loongarch64::LA64PTE;
 
-use crate::{PageTable64, PageTable64Mut, PagingMetaData};
+use crate::{PageTable64, PageTable64Mut, PagingMetaData, SplitRootsMetaData};
+
+#[cfg(target_arch = "loongarch64")]
+#[inline]
+fn la64_flush_tlb(vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#_dbar>
+            //
+            // Only after all previous load/store access operations are completely
+            // executed, the DBAR 0 instruction can be executed; and only after the
+            // execution of DBAR 0 is completed, all subsequent load/store access
+            // operations can be executed.
+            //
+            // <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#_invtlb>
+            //
+            // formats: invtlb op, asid, addr
+            //
+            // op 0x5: Clear all page table entries with G=0 and ASID equal to the
+            // register specified ASID, and VA equal to the register specified VA.
+            //
+            // When the operation indicated by op does not require an ASID, the
+            // general register rj should be set to r0.
+            asm!("dbar 0; invtlb 0x05, $r0, {reg}", reg = in(reg) vaddr.as_usize());
+        } else {
+            // op 0x0: Clear all page table entries
+            asm!("dbar 0; invtlb 0x00, $r0, $r0");
+        }
+    }
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+#[inline]
+fn la64_flush_tlb(_vaddr: Option<VirtAddr>) {}
+
+#[cfg(target_arch = "loongarch64")]
+#[inline]
+fn la64_flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+    use core::arch::asm;
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // op 0x5: Clear all page table entries with G=0, the given ASID
+            // and VA.
+            asm!(
+                "dbar 0; invtlb 0x05, {asid}, {reg}",
+                asid = in(reg) asid as usize,
+                reg = in(reg) vaddr.as_usize()
+            );
+        } else {
+            // op 0x4: Clear all page table entries with G=0 and the given ASID.
+            asm!("dbar 0; invtlb 0x04, {asid}, $r0", asid = in(reg) asid as usize);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+#[inline]
+fn la64_flush_tlb_asid(_asid: u16, _vaddr: Option<VirtAddr>) {}
+
+#[cfg(target_arch = "loongarch64")]
+#[inline]
+unsafe fn la64_activate(root: PhysAddr, asid: Option<u16>) {
+    use core::arch::asm;
+    unsafe {
+        // CSR 0x18: ASID, whose ASID field is bits[9:0]
+        asm!(
+            "csrxchg {asid}, {mask}, 0x18",
+            asid = inout(reg) asid.unwrap_or(0) as usize => _,
+            mask = in(reg) 0x3ffusize
+        );
+        // CSR 0x19: PGDL, the root of the lower half address space
+        asm!("csrwr {root}, 0x19", root = inout(reg) root.as_usize() => _);
+    }
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+#[inline]
+unsafe fn la64_activate(_root: PhysAddr, _asid: Option<u16>) {}
+
+#[cfg(target_arch = "loongarch64")]
+#[inline]
+fn la64_read_root() -> Option<PhysAddr> {
+    let pgdl: usize;
+    unsafe { core::arch::asm!("csrrd {}, 0x19", out(reg) pgdl) };
+    Some(PhysAddr::from(pgdl & !0xfff))
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+#[inline]
+fn la64_read_root() -> Option<PhysAddr> {
+    None
+}
 
 /// Metadata of LoongArch64 page tables.
 #[derive(Copy, Clone, Debug)]
@@ -47,37 +137,35 @@ impl PagingMetaData for LA64MetaData {
     const LEVELS: usize = 3;
     const PA_MAX_BITS: usize = 40;
     const VA_MAX_BITS: usize = 40;
+    // PGDL and PGDH
+    const SPLIT_ROOTS: bool = true;
     type VirtAddr = VirtAddr;
 
     #[inline]
     fn flush_tlb(vaddr: Option<VirtAddr>) {
-        unsafe {
-            if let Some(vaddr) = vaddr {
-                // <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#_dbar>
-                //
-                // Only after all previous load/store access operations are completely
-                // executed, the DBAR 0 instruction can be executed; and only after the
-                // execution of DBAR 0 is completed, all subsequent load/store access
-                // operations can be executed.
-                //
-                // <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#_invtlb>
-                //
-                // formats: invtlb op, asid, addr
-                //
-                // op 0x5: Clear all page table entries with G=0 and ASID equal to the
-                // register specified ASID, and VA equal to the register specified VA.
-                //
-                // When the operation indicated by op does not require an ASID, the
-                // general register rj should be set to r0.
-                asm!("dbar 0; invtlb 0x05, $r0, {reg}", reg = in(reg) vaddr.as_usize());
-            } else {
-                // op 0x0: Clear all page table entries
-                asm!("dbar 0; invtlb 0x00, $r0, $r0");
-            }
-        }
+        la64_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        la64_flush_tlb_asid(asid, vaddr);
+    }
+
+    /// Writes `PGDL`, so the page table translates the lower half of the
+    /// virtual address space, and the ASID field of `ASID`.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { la64_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        la64_read_root()
     }
 }
 
+impl SplitRootsMetaData for LA64MetaData {}
+
 /// loongarch64 page table
 ///
 /// <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#section-multi-level-page-table-structure-supported-by-page-walking>
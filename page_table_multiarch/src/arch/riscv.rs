@@ -1,10 +1,13 @@
 //! RISC-V specific page table structures.
 
-use memory_addr::VirtAddr;
-use page_table_entry::riscv::Rv64PTE;
+use core::marker::PhantomData;
 
-use crate::{PageTable64, PageTable64Mut, PagingMetaData};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use page_table_entry::riscv::{Rv64PTE, Sv32PTE};
 
+use crate::{PageTable32, PageTable32Mut, PageTable64, PageTable64Mut, PagingMetaData};
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 #[inline]
 fn riscv_flush_tlb(vaddr: Option<memory_addr::VirtAddr>) {
     if let Some(vaddr) = vaddr {
@@ -14,6 +17,254 @@ fn riscv_flush_tlb(vaddr: Option<memory_addr::VirtAddr>) {
     }
 }
 
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_flush_tlb(_vaddr: Option<memory_addr::VirtAddr>) {}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_flush_tlb_asid(asid: u16, vaddr: Option<memory_addr::VirtAddr>) {
+    if let Some(vaddr) = vaddr {
+        riscv::asm::sfence_vma(asid as usize, vaddr.as_usize())
+    } else {
+        // x0 as the address operand to flush the whole address space
+        unsafe { core::arch::asm!("sfence.vma x0, {}", in(reg) asid as usize) }
+    }
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_flush_tlb_asid(_asid: u16, _vaddr: Option<memory_addr::VirtAddr>) {}
+
+/// Flushes the TLB entries of the range, of the ASID `asid` if it is given,
+/// with the Svinval extension.
+///
+/// The pages are invalidated by `SINVAL.VMA` one by one, ordered by a single
+/// `SFENCE.W.INVAL` and `SFENCE.INVAL.IR` pair, or the whole TLB is flushed
+/// if there are more than `FLUSH_RANGE_MAX_PAGES`.
+#[cfg(all(
+    feature = "svinval",
+    any(target_arch = "riscv32", target_arch = "riscv64")
+))]
+fn riscv_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    start: VirtAddr,
+    end: VirtAddr,
+    stride: usize,
+    asid: Option<u16>,
+) {
+    use core::arch::asm;
+    let start = start.as_usize();
+    let pages = end.as_usize().wrapping_sub(start) / stride;
+    if pages > M::FLUSH_RANGE_MAX_PAGES {
+        match asid {
+            Some(asid) => riscv_flush_tlb_asid(asid, None),
+            None => riscv_flush_tlb(None),
+        }
+        return;
+    }
+    unsafe {
+        // SFENCE.W.INVAL: order the changed entries before the invalidations
+        asm!(".insn r 0x73, 0, 0x0c, x0, x0, x0");
+        for i in 0..pages {
+            let vaddr = start.wrapping_add(i * stride);
+            // SINVAL.VMA, with x0 as the ASID operand for all address spaces
+            match asid {
+                Some(asid) => asm!(
+                    ".insn r 0x73, 0, 0x0b, x0, {}, {}",
+                    in(reg) vaddr,
+                    in(reg) asid as usize
+                ),
+                None => asm!(".insn r 0x73, 0, 0x0b, x0, {}, x0", in(reg) vaddr),
+            }
+        }
+        // SFENCE.INVAL.IR: order the invalidations before later accesses
+        asm!(".insn r 0x73, 0, 0x0c, x0, x0, x1");
+    }
+}
+
+#[cfg(all(
+    feature = "svinval",
+    not(any(target_arch = "riscv32", target_arch = "riscv64"))
+))]
+#[inline]
+fn riscv_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    _start: VirtAddr,
+    _end: VirtAddr,
+    _stride: usize,
+    _asid: Option<u16>,
+) {
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_flush_gstage_tlb(gpa: Option<usize>) {
+    // HFENCE.GVMA takes the guest physical address shifted right by 2, and
+    // x0 as the VMID operand to flush for all VMIDs.
+    unsafe {
+        if let Some(gpa) = gpa {
+            core::arch::asm!(".insn r 0x73, 0, 0x31, x0, {}, x0", in(reg) gpa >> 2)
+        } else {
+            core::arch::asm!(".insn r 0x73, 0, 0x31, x0, x0, x0")
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_flush_gstage_tlb(_gpa: Option<usize>) {}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_flush_gstage_tlb_vmid(vmid: u16, gpa: Option<usize>) {
+    unsafe {
+        if let Some(gpa) = gpa {
+            core::arch::asm!(
+                ".insn r 0x73, 0, 0x31, x0, {}, {}",
+                in(reg) gpa >> 2,
+                in(reg) vmid as usize
+            )
+        } else {
+            core::arch::asm!(".insn r 0x73, 0, 0x31, x0, x0, {}", in(reg) vmid as usize)
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_flush_gstage_tlb_vmid(_vmid: u16, _gpa: Option<usize>) {}
+
+/// The width of the ASID field of `satp`.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const SATP_ASID_BITS: u32 = if cfg!(target_arch = "riscv64") { 16 } else { 9 };
+/// The width of the VMID field of `hgatp`.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const HGATP_VMID_BITS: u32 = if cfg!(target_arch = "riscv64") { 14 } else { 7 };
+
+/// Encodes the `satp` or `hgatp` value of the given mode, ASID or VMID of
+/// `id_bits` bits, and root page table.
+#[cfg(target_arch = "riscv64")]
+#[inline]
+fn riscv_atp(mode: usize, root: PhysAddr, id: Option<u16>, id_bits: u32) -> usize {
+    let id = id.unwrap_or(0) as usize & ((1 << id_bits) - 1);
+    // MODE => bits[63:60], ASID => bits[59:44] (VMID => bits[57:44]),
+    // PPN => bits[43:0]
+    (mode << 60) | (id << 44) | (root.as_usize() >> 12)
+}
+
+#[cfg(target_arch = "riscv32")]
+#[inline]
+fn riscv_atp(mode: usize, root: PhysAddr, id: Option<u16>, id_bits: u32) -> usize {
+    let id = id.unwrap_or(0) as usize & ((1 << id_bits) - 1);
+    // MODE => bit[31], ASID => bits[30:22] (VMID => bits[28:22]),
+    // PPN => bits[21:0]
+    (mode << 31) | (id << 22) | (root.as_usize() >> 12)
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_atp_root(atp: usize) -> PhysAddr {
+    const PPN_BITS: u32 = if cfg!(target_arch = "riscv64") {
+        44
+    } else {
+        22
+    };
+    PhysAddr::from((atp & ((1 << PPN_BITS) - 1)) << 12)
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+unsafe fn riscv_activate(mode: usize, root: PhysAddr, asid: Option<u16>) {
+    let satp = riscv_atp(mode, root, asid, SATP_ASID_BITS);
+    unsafe { core::arch::asm!("csrw satp, {}", in(reg) satp) }
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+unsafe fn riscv_activate(_mode: usize, _root: PhysAddr, _asid: Option<u16>) {}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_read_root() -> Option<PhysAddr> {
+    let satp: usize;
+    unsafe { core::arch::asm!("csrr {}, satp", out(reg) satp) };
+    Some(riscv_atp_root(satp))
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_read_root() -> Option<PhysAddr> {
+    None
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+unsafe fn riscv_gstage_activate(mode: usize, root: PhysAddr, vmid: Option<u16>) {
+    // CSR 0x680: hgatp, with the same layout as satp, but a narrower VMID
+    let hgatp = riscv_atp(mode, root, vmid, HGATP_VMID_BITS);
+    unsafe { core::arch::asm!("csrw 0x680, {}", in(reg) hgatp) }
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+unsafe fn riscv_gstage_activate(_mode: usize, _root: PhysAddr, _vmid: Option<u16>) {}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[inline]
+fn riscv_gstage_read_root() -> Option<PhysAddr> {
+    let hgatp: usize;
+    unsafe { core::arch::asm!("csrr {}, 0x680", out(reg) hgatp) };
+    Some(riscv_atp_root(hgatp))
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+#[inline]
+fn riscv_gstage_read_root() -> Option<PhysAddr> {
+    None
+}
+
+/// Metadata of RISC-V Sv32 page tables.
+pub struct Sv32MetaData;
+
+impl PagingMetaData for Sv32MetaData {
+    const LEVELS: usize = 2;
+    const PA_MAX_BITS: usize = 34;
+    const VA_MAX_BITS: usize = 32;
+    const LEVEL_INDEX_BITS: usize = 10;
+    const FLUSH_RANGE_MAX_PAGES: usize = 64;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        u32::try_from(vaddr).is_ok()
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[cfg(feature = "svinval")]
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        riscv_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes `satp` with the Sv32 mode.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_activate(1, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_read_root()
+    }
+}
+
 /// Metadata of RISC-V Sv39 page tables.
 pub struct Sv39MetaData;
 
@@ -21,12 +272,35 @@ impl PagingMetaData for Sv39MetaData {
     const LEVELS: usize = 3;
     const PA_MAX_BITS: usize = 56;
     const VA_MAX_BITS: usize = 39;
+    const FLUSH_RANGE_MAX_PAGES: usize = 64;
     type VirtAddr = VirtAddr;
 
     #[inline]
     fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
         riscv_flush_tlb(vaddr);
     }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[cfg(feature = "svinval")]
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        riscv_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes `satp` with the Sv39 mode.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_activate(8, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_read_root()
+    }
 }
 
 /// Metadata of RISC-V Sv48 page tables.
@@ -36,14 +310,162 @@ impl PagingMetaData for Sv48MetaData {
     const LEVELS: usize = 4;
     const PA_MAX_BITS: usize = 56;
     const VA_MAX_BITS: usize = 48;
+    const FLUSH_RANGE_MAX_PAGES: usize = 64;
     type VirtAddr = VirtAddr;
 
     #[inline]
     fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
         riscv_flush_tlb(vaddr);
     }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[cfg(feature = "svinval")]
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        riscv_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes `satp` with the Sv48 mode.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_activate(9, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_read_root()
+    }
 }
 
+/// Metadata of RISC-V Sv57 page tables.
+pub struct Sv57MetaData;
+
+impl PagingMetaData for Sv57MetaData {
+    const LEVELS: usize = 5;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 57;
+    const FLUSH_RANGE_MAX_PAGES: usize = 64;
+    type VirtAddr = VirtAddr;
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb(vaddr);
+    }
+
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        riscv_flush_tlb_asid(asid, vaddr);
+    }
+
+    #[cfg(feature = "svinval")]
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        riscv_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes `satp` with the Sv57 mode.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_activate(10, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_read_root()
+    }
+}
+
+/// Metadata of RISC-V Sv39x4 G-stage page tables.
+///
+/// The root table has 2048 entries (16K) to translate 41-bit guest physical
+/// addresses of type `GPA`, so it is allocated as 4 contiguous frames aligned
+/// to 16K. G-stage accesses are checked as user-mode accesses, so mappings
+/// must have [`MappingFlags::USER`](crate::MappingFlags::USER) set.
+pub struct Sv39x4MetaData<GPA: MemoryAddr = VirtAddr>(PhantomData<fn() -> GPA>);
+
+impl<GPA: MemoryAddr> PagingMetaData for Sv39x4MetaData<GPA> {
+    const LEVELS: usize = 3;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 41;
+    const ROOT_INDEX_BITS: usize = 11;
+    type VirtAddr = GPA;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        // guest physical addresses are not sign-extended
+        vaddr >> Self::VA_MAX_BITS == 0
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<GPA>) {
+        riscv_flush_gstage_tlb(vaddr.map(Into::into));
+    }
+
+    /// Flushes the G-stage TLB entries of the VMID `asid`.
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<GPA>) {
+        riscv_flush_gstage_tlb_vmid(asid, vaddr.map(Into::into));
+    }
+
+    /// Writes `hgatp` with the Sv39x4 mode, and the VMID `asid`.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_gstage_activate(8, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_gstage_read_root()
+    }
+}
+
+/// Metadata of RISC-V Sv48x4 G-stage page tables.
+///
+/// Like [`Sv39x4MetaData`], but with 4 levels and 50-bit guest physical
+/// addresses.
+pub struct Sv48x4MetaData<GPA: MemoryAddr = VirtAddr>(PhantomData<fn() -> GPA>);
+
+impl<GPA: MemoryAddr> PagingMetaData for Sv48x4MetaData<GPA> {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 50;
+    const ROOT_INDEX_BITS: usize = 11;
+    type VirtAddr = GPA;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        vaddr >> Self::VA_MAX_BITS == 0
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<GPA>) {
+        riscv_flush_gstage_tlb(vaddr.map(Into::into));
+    }
+
+    /// Flushes the G-stage TLB entries of the VMID `asid`.
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<GPA>) {
+        riscv_flush_gstage_tlb_vmid(asid, vaddr.map(Into::into));
+    }
+
+    /// Writes `hgatp` with the Sv48x4 mode, and the VMID `asid`.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { riscv_gstage_activate(9, root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        riscv_gstage_read_root()
+    }
+}
+
+/// Sv32: Page-Based 32-bit (2 levels) Virtual-Memory System.
+pub type Sv32PageTable<H> = PageTable32<Sv32MetaData, Sv32PTE, H>;
+pub type Sv32PageTableMut<'a, H> = PageTable32Mut<'a, Sv32MetaData, Sv32PTE, H>;
+
 /// Sv39: Page-Based 39-bit (3 levels) Virtual-Memory System.
 pub type Sv39PageTable<H> = PageTable64<Sv39MetaData, Rv64PTE, H>;
 pub type Sv39PageTableMut<'a, H> = PageTable64Mut<'a, Sv39MetaData, Rv64PTE, H>;
@@ -51,3 +473,17 @@ pub type Sv39PageTableMut<'a, H> = PageTable64Mut<'a, Sv39MetaData, Rv64PTE, H>;
 /// Sv48: Page-Based 48-bit (4 levels) Virtual-Memory System.
 pub type Sv48PageTable<H> = PageTable64<Sv48MetaData, Rv64PTE, H>;
 pub type Sv48PageTableMut<'a, H> = PageTable64Mut<'a, Sv48MetaData, Rv64PTE, H>;
+
+/// Sv57: Page-Based 57-bit (5 levels) Virtual-Memory System.
+pub type Sv57PageTable<H> = PageTable64<Sv57MetaData, Rv64PTE, H>;
+pub type Sv57PageTableMut<'a, H> = PageTable64Mut<'a, Sv57MetaData, Rv64PTE, H>;
+
+/// Sv39x4: G-stage address translation for Sv39 guests.
+pub type Sv39x4PageTable<H, GPA = VirtAddr> = PageTable64<Sv39x4MetaData<GPA>, Rv64PTE, H>;
+pub type Sv39x4PageTableMut<'a, H, GPA = VirtAddr> =
+    PageTable64Mut<'a, Sv39x4MetaData<GPA>, Rv64PTE, H>;
+
+/// Sv48x4: G-stage address translation for Sv48 guests.
+pub type Sv48x4PageTable<H, GPA = VirtAddr> = PageTable64<Sv48x4MetaData<GPA>, Rv64PTE, H>;
+pub type Sv48x4PageTableMut<'a, H, GPA = VirtAddr> =
+    PageTable64Mut<'a, Sv48x4MetaData<GPA>, Rv64PTE, H>;
@@ -1,9 +1,193 @@
 //! x86 specific page table structures.
 
-use memory_addr::VirtAddr;
-use page_table_entry::x86_64::X64PTE;
+use core::marker::PhantomData;
 
-use crate::{PageTable64, PageTable64Mut, PagingMetaData};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use page_table_entry::x86_64::{EPTEntry, X64PTE, X86PTE32};
+
+use crate::{PageTable32, PageTable32Mut, PageTable64, PageTable64Mut, PagingMetaData};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+fn x86_flush_tlb(vaddr: Option<VirtAddr>) {
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            x86::tlb::flush(vaddr.into());
+        } else {
+            x86::tlb::flush_all();
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+#[inline]
+fn x86_flush_tlb(_vaddr: Option<VirtAddr>) {}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+unsafe fn x86_activate(root: PhysAddr, pcid: Option<u16>) {
+    use x86::controlregs::{Cr4, cr3_write, cr4};
+    // CR3 holds the PCID in bits 11:0 only when CR4.PCIDE is set, otherwise
+    // they are PWT, PCD and reserved bits
+    let pcid = match pcid {
+        Some(pcid) if unsafe { cr4() }.contains(Cr4::CR4_ENABLE_PCID) => pcid as u64 & 0xfff,
+        _ => 0,
+    };
+    unsafe { cr3_write(root.as_usize() as u64 | pcid) }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+#[inline]
+unsafe fn x86_activate(_root: PhysAddr, _pcid: Option<u16>) {}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+fn x86_read_root() -> Option<PhysAddr> {
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+    let cr3 = unsafe { x86::controlregs::cr3() };
+    Some(PhysAddr::from((cr3 & ADDR_MASK) as usize))
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+#[inline]
+fn x86_read_root() -> Option<PhysAddr> {
+    None
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn x86_flush_tlb_pcid(pcid: u16, vaddr: Option<VirtAddr>) {
+    // INVPCID type 0: individual-address invalidation, and type 1:
+    // single-context invalidation. Both keep global translations.
+    let (kind, addr) = match vaddr {
+        Some(vaddr) => (0u64, vaddr.as_usize() as u64),
+        None => (1, 0),
+    };
+    let descriptor = [pcid as u64, addr];
+    unsafe {
+        core::arch::asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn x86_flush_tlb_pcid(_pcid: u16, _vaddr: Option<VirtAddr>) {}
+
+/// The INVLPGB support of the CPU, detected on the first range flush: bit 31
+/// is set once it is detected, bit 16 if INVLPGB is supported, and bits[15:0]
+/// hold the maximum number of extra pages that one INVLPGB invalidates.
+#[cfg(target_arch = "x86_64")]
+static INVLPGB_INFO: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+#[cfg(target_arch = "x86_64")]
+fn invlpgb_max_pages() -> Option<u16> {
+    use core::sync::atomic::Ordering;
+    let mut info = INVLPGB_INFO.load(Ordering::Relaxed);
+    if info == 0 {
+        info = 1 << 31;
+        // `__cpuid` is only unsafe in older Rust versions, such as the MSRV
+        #[allow(unused_unsafe)]
+        let (max_leaf, cap) = unsafe {
+            use core::arch::x86_64::__cpuid;
+            (__cpuid(0x8000_0000).eax, __cpuid(0x8000_0008))
+        };
+        // CPUID 0x8000_0008: INVLPGB => EBX bit 3, and the maximum number of
+        // extra pages => EDX bits[15:0]
+        if max_leaf >= 0x8000_0008 && cap.ebx & (1 << 3) != 0 {
+            info |= (1 << 16) | (cap.edx & 0xffff);
+        }
+        INVLPGB_INFO.store(info, Ordering::Relaxed);
+    }
+    (info & (1 << 16) != 0).then_some(info as u16)
+}
+
+/// Flushes the TLB entries of the range, of the PCID `pcid` if it is given.
+///
+/// With INVLPGB, the range is invalidated on all CPUs, a batch of pages per
+/// instruction. Otherwise, the pages are flushed on the current CPU one by
+/// one, or the whole TLB if there are more than `FLUSH_RANGE_MAX_PAGES`.
+#[cfg(target_arch = "x86_64")]
+fn x86_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    start: VirtAddr,
+    end: VirtAddr,
+    stride: usize,
+    pcid: Option<u16>,
+) {
+    use core::arch::asm;
+    let flush_tlb = |vaddr| match pcid {
+        Some(pcid) => M::flush_tlb_asid(pcid, vaddr),
+        None => M::flush_tlb(vaddr),
+    };
+    let start = start.as_usize();
+    let pages = end.as_usize().wrapping_sub(start) / stride;
+    if pages > M::FLUSH_RANGE_MAX_PAGES {
+        flush_tlb(None);
+        return;
+    }
+    let Some(max_pages) = invlpgb_max_pages() else {
+        for i in 0..pages {
+            flush_tlb(Some(start.wrapping_add(i * stride).into()));
+        }
+        return;
+    };
+
+    // rAX: VA => bits[63:12], with the valid bits of the VA (bit 0), the PCID
+    // (bit 1) and global translations (bit 3). EDX: PCID => bits[27:16].
+    let (filter, edx) = match pcid {
+        Some(pcid) => (0b0011, (pcid as u32 & 0xfff) << 16),
+        None => (0b1001, 0),
+    };
+    // ECX: the number of extra pages => bits[15:0], and whether the pages are
+    // 2M (bit 31) rather than 4K. Other pages are invalidated one by one.
+    let (batch, stride_bit) = match stride {
+        0x1000 => (max_pages as usize + 1, 0),
+        0x20_0000 => (max_pages as usize + 1, 1 << 31),
+        _ => (1, 0),
+    };
+    let mut vaddr = start;
+    let mut left = pages;
+    while left > 0 {
+        let count = left.min(batch);
+        unsafe {
+            // INVLPGB, encoded as bytes for older assemblers
+            asm!(
+                ".byte 0x0f, 0x01, 0xfe",
+                in("rax") (vaddr & !0xfff) as u64 | filter,
+                in("ecx") (count - 1) as u32 | stride_bit,
+                in("edx") edx,
+                options(nostack)
+            );
+        }
+        vaddr = vaddr.wrapping_add(count * stride);
+        left -= count;
+    }
+    // TLBSYNC: wait for the invalidations on other CPUs
+    unsafe { asm!(".byte 0x0f, 0x01, 0xff", options(nostack)) };
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn x86_flush_tlb_range<M: PagingMetaData<VirtAddr = VirtAddr>>(
+    _start: VirtAddr,
+    _end: VirtAddr,
+    _stride: usize,
+    _pcid: Option<u16>,
+) {
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn invept_all_context() {
+    // INVEPT type 2: all-context invalidation, the descriptor is not used.
+    let descriptor = [0u64; 2];
+    unsafe {
+        core::arch::asm!("invept {}, [{}]", in(reg) 2u64, in(reg) &descriptor);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn invept_all_context() {}
 
 /// metadata of x86_64 page tables.
 pub struct X64PagingMetaData;
@@ -16,16 +200,147 @@ impl PagingMetaData for X64PagingMetaData {
 
     #[inline]
     fn flush_tlb(vaddr: Option<VirtAddr>) {
-        unsafe {
-            if let Some(vaddr) = vaddr {
-                x86::tlb::flush(vaddr.into());
-            } else {
-                x86::tlb::flush_all();
-            }
-        }
+        x86_flush_tlb(vaddr);
+    }
+
+    /// Flushes the TLB entries of the PCID `asid` with INVPCID, which must be
+    /// supported by the CPU.
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        x86_flush_tlb_pcid(asid, vaddr);
+    }
+
+    /// Flushes the range with INVLPGB if the CPU supports it, which also
+    /// invalidates it on other CPUs.
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        x86_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes CR3, with the PCID `asid` if it is given and CR4.PCIDE is set.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { x86_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        x86_read_root()
+    }
+}
+
+/// metadata of x86_64 page tables with 5-level paging (LA57).
+pub struct X64LA57PagingMetaData;
+
+impl PagingMetaData for X64LA57PagingMetaData {
+    const LEVELS: usize = 5;
+    const PA_MAX_BITS: usize = 52;
+    const VA_MAX_BITS: usize = 57;
+    type VirtAddr = VirtAddr;
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        x86_flush_tlb(vaddr);
+    }
+
+    /// Flushes the TLB entries of the PCID `asid` with INVPCID, which must be
+    /// supported by the CPU.
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<VirtAddr>) {
+        x86_flush_tlb_pcid(asid, vaddr);
+    }
+
+    /// Flushes the range with INVLPGB if the CPU supports it, which also
+    /// invalidates it on other CPUs.
+    #[inline]
+    fn flush_tlb_range(start: VirtAddr, end: VirtAddr, stride: usize, asid: Option<u16>) {
+        x86_flush_tlb_range::<Self>(start, end, stride, asid);
+    }
+
+    /// Writes CR3, with the PCID `asid` if it is given and CR4.PCIDE is set.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        unsafe { x86_activate(root, asid) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        x86_read_root()
+    }
+}
+
+/// metadata of x86 extended page tables (EPT).
+///
+/// The page table translates guest-physical addresses of type `GPA` to
+/// host-physical addresses, with the same geometry as 4-level x86_64 paging.
+pub struct ExtendedPageTable<GPA: MemoryAddr = VirtAddr>(PhantomData<fn() -> GPA>);
+
+impl<GPA: MemoryAddr> PagingMetaData for ExtendedPageTable<GPA> {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 52;
+    const VA_MAX_BITS: usize = 48;
+    const FLUSH_RANGE_MAX_PAGES: usize = 0; // every flush invalidates all EPTs
+    type VirtAddr = GPA;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        // guest-physical addresses are not sign-extended
+        vaddr >> Self::VA_MAX_BITS == 0
+    }
+
+    /// Invalidates the mappings of all EPTs with INVEPT.
+    ///
+    /// INVEPT cannot invalidate a single guest-physical address, so `vaddr`
+    /// is ignored.
+    #[inline]
+    fn flush_tlb(_vaddr: Option<GPA>) {
+        invept_all_context();
+    }
+}
+
+/// metadata of x86 page tables with 32-bit (non-PAE) paging.
+pub struct X86PagingMetaData32;
+
+impl PagingMetaData for X86PagingMetaData32 {
+    const LEVELS: usize = 2;
+    const PA_MAX_BITS: usize = 32;
+    const VA_MAX_BITS: usize = 32;
+    const LEVEL_INDEX_BITS: usize = 10;
+    type VirtAddr = VirtAddr;
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        u32::try_from(vaddr).is_ok()
+    }
+
+    #[inline]
+    fn flush_tlb(vaddr: Option<VirtAddr>) {
+        x86_flush_tlb(vaddr);
+    }
+
+    /// Writes CR3. 32-bit paging has no PCIDs, so `asid` is ignored.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, _asid: Option<u16>) {
+        unsafe { x86_activate(root, None) }
+    }
+
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        x86_read_root()
     }
 }
 
 /// x86_64 page table.
 pub type X64PageTable<H> = PageTable64<X64PagingMetaData, X64PTE, H>;
 pub type X64PageTableMut<'a, H> = PageTable64Mut<'a, X64PagingMetaData, X64PTE, H>;
+
+/// x86_64 page table with 5-level paging (LA57).
+pub type X64LA57PageTable<H> = PageTable64<X64LA57PagingMetaData, X64PTE, H>;
+pub type X64LA57PageTableMut<'a, H> = PageTable64Mut<'a, X64LA57PagingMetaData, X64PTE, H>;
+
+/// x86 page table with 32-bit (non-PAE) paging.
+pub type X86PageTable32<H> = PageTable32<X86PagingMetaData32, X86PTE32, H>;
+pub type X86PageTable32Mut<'a, H> = PageTable32Mut<'a, X86PagingMetaData32, X86PTE32, H>;
+
+/// x86 extended page table (EPT).
+pub type EPTPageTable<H, GPA = VirtAddr> = PageTable64<ExtendedPageTable<GPA>, EPTEntry, H>;
+pub type EPTPageTableMut<'a, H, GPA = VirtAddr> =
+    PageTable64Mut<'a, ExtendedPageTable<GPA>, EPTEntry, H>;
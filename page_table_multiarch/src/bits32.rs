@@ -0,0 +1,15 @@
+use crate::{PageTable64, PageTable64Mut};
+
+/// A generic page table struct for 32-bit platform.
+///
+/// Walking a 32-bit page table only differs in the geometry described by the
+/// [`PagingMetaData`](crate::PagingMetaData) and in the size of the entries,
+/// so this is [`PageTable64`] with 32-bit entries. The alias is intended:
+/// despite its name, [`PageTable64`] is the page table engine for all widths,
+/// and this name only documents the intent at the use site. The `64` refers
+/// to the platforms it was first written for, not to the entry size, which
+/// is that of `PTE`.
+pub type PageTable32<M, PTE, H> = PageTable64<M, PTE, H>;
+
+/// A mutable reference to a [`PageTable32`], see [`PageTable64Mut`].
+pub type PageTable32Mut<'a, M, PTE, H> = PageTable64Mut<'a, M, PTE, H>;
@@ -1,51 +1,169 @@
-use core::{marker::PhantomData, ops::Deref};
+mod cursor;
+mod mappings;
+mod split;
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, Range},
+};
 
 use arrayvec::ArrayVec;
-use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr};
+use memory_addr::{MemoryAddr, PhysAddr};
 
 use crate::{
     GenericPTE, MappingFlags, PageSize, PagingError, PagingHandler, PagingMetaData, PagingResult,
+    TlbFlush, TlbRange,
+};
+
+pub use self::{
+    cursor::Cursor,
+    mappings::Mappings,
+    split::{SplitAddressSpace, SplitRootsMetaData, SplitSpaceMetaData},
 };
 
-const ENTRY_COUNT: usize = 512;
+/// The maximum number of root entries that can be borrowed by
+/// [`PageTable64Mut::copy_from`].
+#[cfg(feature = "copy-from")]
+const MAX_ROOT_ENTRIES: usize = 1024;
+
+/// The number of entries in the root page table.
+const fn root_entry_count<M: PagingMetaData>() -> usize {
+    1 << M::ROOT_INDEX_BITS
+}
+
+/// The number of entries in a non-root page table.
+const fn entry_count<M: PagingMetaData>() -> usize {
+    1 << M::LEVEL_INDEX_BITS
+}
+
+/// The lowest virtual address bit indexed at `level` (0 is the root level).
+///
+/// An entry at this level maps `1 << level_shift(level)` bytes.
+const fn level_shift<M: PagingMetaData>(level: usize) -> usize {
+    M::PAGE_SHIFT + (M::LEVELS - 1 - level) * M::LEVEL_INDEX_BITS
+}
+
+const fn level_index<M: PagingMetaData>(vaddr: usize, level: usize) -> usize {
+    let count = if level == 0 {
+        root_entry_count::<M>()
+    } else {
+        entry_count::<M>()
+    };
+    (vaddr >> level_shift::<M>(level)) & (count - 1)
+}
+
+/// The number of base page frames occupied by the root page table.
+const fn root_frame_count<M: PagingMetaData, PTE: GenericPTE>() -> usize {
+    (root_entry_count::<M>() * size_of::<PTE>()).div_ceil(1 << M::PAGE_SHIFT)
+}
 
-const fn p4_index(vaddr: usize) -> usize {
-    (vaddr >> (12 + 27)) & (ENTRY_COUNT - 1)
+/// The page size mapped by a leaf entry at `level`, or [`None`] if there is no
+/// such page size and the entry can only point to a next level table.
+fn level_page_size<M: PagingMetaData>(level: usize) -> Option<PageSize> {
+    PageSize::try_from(1 << level_shift::<M>(level)).ok()
 }
 
-const fn p3_index(vaddr: usize) -> usize {
-    (vaddr >> (12 + 18)) & (ENTRY_COUNT - 1)
+/// The level whose leaf entries map pages of `page_size`.
+fn page_size_level<M: PagingMetaData>(page_size: PageSize) -> Option<usize> {
+    (0..M::LEVELS).find(|&level| level_page_size::<M>(level) == Some(page_size))
 }
 
-const fn p2_index(vaddr: usize) -> usize {
-    (vaddr >> (12 + 9)) & (ENTRY_COUNT - 1)
+/// The size of the base page, i.e. of the pages mapped by the last level.
+fn base_page_size<M: PagingMetaData>() -> PageSize {
+    level_page_size::<M>(M::LEVELS - 1).expect("unsupported base page size")
 }
 
-const fn p1_index(vaddr: usize) -> usize {
-    (vaddr >> 12) & (ENTRY_COUNT - 1)
+/// Whether pages of `page_size` are mapped by block entries above the last
+/// level.
+fn is_huge_page<M: PagingMetaData>(page_size: PageSize) -> bool {
+    page_size != base_page_size::<M>()
+}
+
+/// The error for a leaf entry of a page of `size` at `vaddr` that is not
+/// present.
+fn not_mapped<M: PagingMetaData>(vaddr: usize, size: PageSize) -> PagingError {
+    PagingError::NotMapped {
+        vaddr,
+        level: page_size_level::<M>(size).unwrap_or(M::LEVELS - 1),
+    }
+}
+
+/// The error for a non-present leaf `entry`, which tells swap entries apart.
+fn not_present<M: PagingMetaData, PTE: GenericPTE>(
+    entry: &PTE,
+    vaddr: usize,
+    size: PageSize,
+) -> PagingError {
+    entry
+        .swap_payload()
+        .map_or_else(|| not_mapped::<M>(vaddr, size), PagingError::Swapped)
+}
+
+/// The error for a walk of `vaddr` that stops at `entry` of `level`, which
+/// does not point to a next level table.
+fn walk_error<PTE: GenericPTE>(entry: &PTE, vaddr: usize, level: usize) -> PagingError {
+    if entry.paddr().as_usize() == 0 {
+        PagingError::NotMapped { vaddr, level }
+    } else {
+        PagingError::MappedToHugePage { vaddr, level }
+    }
+}
+
+/// Checks that the page table can map `vaddr` to `paddr`.
+fn check_addrs<M: PagingMetaData>(vaddr: usize, paddr: PhysAddr) -> PagingResult {
+    if !M::vaddr_is_valid(vaddr) {
+        return Err(PagingError::InvalidVirtAddr(vaddr));
+    }
+    if !M::paddr_is_valid(paddr.as_usize()) {
+        return Err(PagingError::InvalidPhysAddr(paddr));
+    }
+    Ok(())
 }
 
 /// A generic page table struct for 64-bit platform.
 ///
+/// 32-bit page tables share this implementation, see [`PageTable32`](crate::PageTable32).
+///
 /// It also tracks all intermediate level tables. They will be deallocated
 /// When the [`PageTable64`] itself is dropped.
 pub struct PageTable64<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> {
     root_paddr: PhysAddr,
     #[cfg(feature = "copy-from")]
-    borrowed_entries: bitmaps::Bitmap<ENTRY_COUNT>,
-    _phantom: PhantomData<(M, PTE, H)>,
+    borrowed_entries: bitmaps::Bitmap<MAX_ROOT_ENTRIES>,
+    asid: Option<u16>,
+    /// Whether the page table is not in use by any CPU, so the TLB needs no
+    /// flush when it is changed.
+    detached: bool,
+    handler: H,
+    _phantom: PhantomData<(M, PTE)>,
 }
 
-impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64<M, PTE, H> {
-    /// Creates a new page table instance or returns the error.
+impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler + Default> PageTable64<M, PTE, H> {
+    /// Creates a new page table instance with the default handler or returns
+    /// the error.
     ///
-    /// It will allocate a new page for the root page table.
+    /// See [`PageTable64::try_new_in`].
     pub fn try_new() -> PagingResult<Self> {
-        let root_paddr = Self::alloc_table()?;
+        Self::try_new_in(H::default())
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64<M, PTE, H> {
+    /// Creates a new page table instance that allocates and accesses its
+    /// tables through `handler`, or returns the error.
+    ///
+    /// It will allocate a new page for the root page table, or contiguous pages
+    /// aligned to their total size if the root page table is larger than a
+    /// page (see [`PagingHandler::alloc_frames`]).
+    pub fn try_new_in(handler: H) -> PagingResult<Self> {
+        let root_paddr = Self::alloc_root(&handler)?;
         Ok(Self {
             root_paddr,
             #[cfg(feature = "copy-from")]
             borrowed_entries: bitmaps::Bitmap::new(),
+            asid: None,
+            detached: false,
+            handler,
             _phantom: PhantomData,
         })
     }
@@ -55,122 +173,267 @@ impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64<M, PTE, H
         self.root_paddr
     }
 
+    /// Returns the address space identifier that the TLB is flushed for, see
+    /// [`set_asid`](Self::set_asid).
+    pub const fn asid(&self) -> Option<u16> {
+        self.asid
+    }
+
+    /// Sets the address space identifier (ASID, PCID or VMID) that this page
+    /// table is used with.
+    ///
+    /// If it is set, [`PageTable64Mut::commit`] flushes the TLB by
+    /// [`PagingMetaData::flush_tlb_asid`], so the entries of other address
+    /// spaces are kept. Otherwise, it flushes by [`PagingMetaData::flush_tlb`].
+    pub fn set_asid(&mut self, asid: Option<u16>) {
+        self.asid = asid;
+    }
+
+    /// Returns whether the page table is detached, see
+    /// [`detach`](Self::detach).
+    pub const fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Marks the page table as not in use by any CPU, e.g., while a new
+    /// address space is set up, or when a guest's page table is edited from
+    /// the host.
+    ///
+    /// Changes to a detached page table are committed without flushing the
+    /// TLB or calling [`PagingHandler::shootdown`]. Call
+    /// [`activate`](Self::activate) before it is used again.
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    /// Installs the page table as the current one of this CPU, with its ASID
+    /// if it is set, by [`PagingMetaData::activate`].
+    ///
+    /// It then flushes the whole TLB of the current CPU, or all entries of the
+    /// ASID if it is set, and marks a detached page table as in use again.
+    ///
+    /// # Safety
+    ///
+    /// The page table must map the code and data in use by the current CPU.
+    pub unsafe fn activate(&mut self) {
+        unsafe { M::activate(self.root_paddr, self.asid) };
+        self.detached = false;
+        match self.asid {
+            Some(asid) => M::flush_tlb_asid(asid, None),
+            None => M::flush_tlb(None),
+        }
+    }
+
+    /// Returns whether the page table is the current one of this CPU, by
+    /// [`PagingMetaData::read_current_root`].
+    pub fn is_current(&self) -> bool {
+        M::read_current_root() == Some(self.root_paddr)
+    }
+
+    /// Returns the handler of this page table.
+    pub const fn handler(&self) -> &H {
+        &self.handler
+    }
+
     /// Queries the result of the mapping starts with `vaddr`.
     ///
     /// Returns the physical address of the target frame, mapping flags, and
     /// the page size.
     ///
-    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
-    /// mapping is not present.
+    /// Returns [`Err(PagingError::Swapped)`](PagingError::Swapped) with the
+    /// payload if the entry is set by [`PageTable64Mut::set_swap`], or
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the mapping
+    /// is not present otherwise.
     pub fn query(&self, vaddr: M::VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
         let (entry, size) = self.get_entry(vaddr)?;
         if !entry.is_present() {
-            return Err(PagingError::NotMapped);
+            return Err(not_present::<M, _>(entry, vaddr.into(), size));
         }
         let off = size.align_offset(vaddr.into());
         Ok((entry.paddr().add(off), entry.flags(), size))
     }
 
+    /// Returns the software bits of the mapping starts with `vaddr`, see
+    /// [`GenericPTE::sw_bits`].
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn sw_bits(&self, vaddr: M::VirtAddr) -> PagingResult<usize> {
+        let (entry, size) = self.get_entry(vaddr)?;
+        if !entry.is_present() {
+            return Err(not_mapped::<M>(vaddr.into(), size));
+        }
+        Ok(entry.sw_bits())
+    }
+
+    /// Returns an iterator over the leaf mappings in the virtual address
+    /// `range`. See [`Mappings`].
+    pub fn mappings(&self, range: Range<M::VirtAddr>) -> Mappings<'_, M, PTE, H> {
+        Mappings::new(self, range)
+    }
+
     pub fn to_mut(&mut self) -> PageTable64Mut<'_, M, PTE, H> {
         PageTable64Mut::new(self)
     }
 }
 
+impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler + Clone> PageTable64<M, PTE, H> {
+    /// Creates a new page table that shares the mappings in the virtual address
+    /// `range` with this one, copy-on-write.
+    ///
+    /// The tables in the range are duplicated, while the mapped frames are
+    /// shared. Writable user mappings are made read-only in both page tables
+    /// and marked copy-on-write (see [`GenericPTE::is_cow`]), and the TLB of
+    /// this page table is flushed. Leaves that are partly in the range are
+    /// shared as a whole. Use [`PageTable64Mut::resolve_cow`] to handle the
+    /// write faults on them. `PTE` must keep the mark, which the default
+    /// [`GenericPTE::set_cow`] does not.
+    ///
+    /// The caller is responsible for keeping track of the shared frames. On
+    /// failure, the mappings of this page table may already be marked
+    /// copy-on-write.
+    pub fn fork_cow(&mut self, range: Range<M::VirtAddr>) -> PagingResult<Self> {
+        let mut child = Self::try_new_in(self.handler.clone())?;
+        let (start, end): (usize, usize) = (range.start.into(), range.end.into());
+        if start < end {
+            let mut pt = self.to_mut();
+            let mut child_pt = child.to_mut();
+            let (src, dst) = (pt.root_table_mut(), child_pt.root_table_mut());
+            pt.fork_table(&mut child_pt, src, dst, 0, start, end - 1)?;
+        }
+        Ok(child)
+    }
+}
+
 // Private implements.
 impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64<M, PTE, H> {
-    fn alloc_table() -> PagingResult<PhysAddr> {
-        if let Some(paddr) = H::alloc_frame() {
-            let ptr = H::phys_to_virt(paddr).as_mut_ptr();
-            unsafe { core::ptr::write_bytes(ptr, 0, PAGE_SIZE_4K) };
+    fn alloc_table(&self) -> PagingResult<PhysAddr> {
+        if let Some(paddr) = self.handler.alloc_frame() {
+            let ptr = self.handler.phys_to_virt(paddr).as_mut_ptr();
+            unsafe { core::ptr::write_bytes(ptr, 0, 1 << M::PAGE_SHIFT) };
+            Ok(paddr)
+        } else {
+            Err(PagingError::NoMemory)
+        }
+    }
+
+    fn alloc_root(handler: &H) -> PagingResult<PhysAddr> {
+        let size = root_frame_count::<M, PTE>() << M::PAGE_SHIFT;
+        if let Some(paddr) = handler.alloc_frames(root_frame_count::<M, PTE>(), size) {
+            let ptr = handler.phys_to_virt(paddr).as_mut_ptr();
+            unsafe { core::ptr::write_bytes(ptr, 0, size) };
             Ok(paddr)
         } else {
             Err(PagingError::NoMemory)
         }
     }
 
+    fn root_table<'a>(&self) -> &'a [PTE] {
+        let ptr = self.handler.phys_to_virt(self.root_paddr).as_ptr() as _;
+        unsafe { core::slice::from_raw_parts(ptr, root_entry_count::<M>()) }
+    }
+
     fn table_of<'a>(&self, paddr: PhysAddr) -> &'a [PTE] {
-        let ptr = H::phys_to_virt(paddr).as_ptr() as _;
-        unsafe { core::slice::from_raw_parts(ptr, ENTRY_COUNT) }
+        let ptr = self.handler.phys_to_virt(paddr).as_ptr() as _;
+        unsafe { core::slice::from_raw_parts(ptr, entry_count::<M>()) }
     }
 
-    fn next_table<'a>(&self, entry: &PTE) -> PagingResult<&'a [PTE]> {
-        if entry.paddr().as_usize() == 0 {
-            Err(PagingError::NotMapped)
-        } else if entry.is_huge() {
-            Err(PagingError::MappedToHugePage)
+    /// Returns the next level table that `entry` points to, or [`None`] if it
+    /// is not present or maps a huge page, see [`walk_error`].
+    fn next_table<'a>(&self, entry: &PTE) -> Option<&'a [PTE]> {
+        if entry.paddr().as_usize() == 0 || entry.is_huge() {
+            None
         } else {
-            Ok(self.table_of(entry.paddr()))
+            Some(self.table_of(entry.paddr()))
         }
     }
 
     fn get_entry(&self, vaddr: M::VirtAddr) -> PagingResult<(&PTE, PageSize)> {
         let vaddr: usize = vaddr.into();
-        let p3 = if M::LEVELS == 3 {
-            self.table_of(self.root_paddr())
-        } else if M::LEVELS == 4 {
-            let p4 = self.table_of(self.root_paddr());
-            let p4e = &p4[p4_index(vaddr)];
-            self.next_table(p4e)?
-        } else {
-            unreachable!()
-        };
-        let p3e = &p3[p3_index(vaddr)];
-        if p3e.is_huge() {
-            return Ok((p3e, PageSize::Size1G));
-        }
-
-        let p2 = self.next_table(p3e)?;
-        let p2e = &p2[p2_index(vaddr)];
-        if p2e.is_huge() {
-            return Ok((p2e, PageSize::Size2M));
+        let mut table = self.root_table();
+        for level in 0..M::LEVELS - 1 {
+            let entry = &table[level_index::<M>(vaddr, level)];
+            if entry.is_huge() {
+                if let Some(size) = level_page_size::<M>(level) {
+                    return Ok((entry, size));
+                }
+            }
+            table = self
+                .next_table(entry)
+                .ok_or_else(|| walk_error(entry, vaddr, level))?;
         }
-
-        let p1 = self.next_table(p2e)?;
-        let p1e = &p1[p1_index(vaddr)];
-        Ok((p1e, PageSize::Size4K))
+        let entry = &table[level_index::<M>(vaddr, M::LEVELS - 1)];
+        Ok((entry, base_page_size::<M>()))
     }
 
     fn dealloc_tree(&self, table_paddr: PhysAddr, level: usize) {
         // don't free the entries in last level, they are not array.
         if level < M::LEVELS - 1 {
             for entry in self.table_of(table_paddr) {
-                if self.next_table(entry).is_ok() {
+                if self.next_table(entry).is_some() {
                     self.dealloc_tree(entry.paddr(), level + 1);
                 }
             }
         }
-        H::dealloc_frame(table_paddr);
+        self.handler.dealloc_frame(table_paddr);
     }
 }
 
 impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Drop for PageTable64<M, PTE, H> {
     fn drop(&mut self) {
-        let root = self.table_of(self.root_paddr);
+        let root = self.root_table();
         #[allow(unused_variables)]
         for (i, entry) in root.iter().enumerate() {
             #[cfg(feature = "copy-from")]
-            if self.borrowed_entries.get(i) {
+            if i < MAX_ROOT_ENTRIES && self.borrowed_entries.get(i) {
                 continue;
             }
-            if self.next_table(entry).is_ok() {
+            if self.next_table(entry).is_some() {
                 self.dealloc_tree(entry.paddr(), 1);
             }
         }
-        H::dealloc_frame(self.root_paddr());
+        self.handler
+            .dealloc_frames(self.root_paddr(), root_frame_count::<M, PTE>());
     }
 }
 
-const SMALL_FLUSH_THRESHOLD: usize = 4;
+/// The maximum number of separate ranges to flush, before the whole TLB is
+/// flushed instead.
+const MAX_FLUSH_RANGES: usize = 4;
+
+/// The maximum number of freed tables kept until the next commit.
+const MAX_FREED_TABLES: usize = 16;
+
+impl TlbRange {
+    /// Extends the range to cover the page `[start, end)`, if it is of the
+    /// same size and adjacent to or inside the range.
+    fn try_merge(&mut self, start: usize, end: usize) -> bool {
+        if end.wrapping_sub(start) != self.stride {
+            false
+        } else if start == self.end {
+            self.end = end;
+            true
+        } else if end == self.start {
+            self.start = start;
+            true
+        } else {
+            start.wrapping_sub(self.start) < self.end.wrapping_sub(self.start)
+        }
+    }
+}
 
-enum ToFlush<M: PagingMetaData> {
+enum ToFlush {
     None,
-    Addresses(ArrayVec<M::VirtAddr, SMALL_FLUSH_THRESHOLD>),
+    Ranges(ArrayVec<TlbRange, MAX_FLUSH_RANGES>),
     Full,
 }
 
 pub struct PageTable64Mut<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> {
     inner: &'a mut PageTable64<M, PTE, H>,
-    flush: ToFlush<M>,
+    flush: ToFlush,
+    /// Tables that are no longer referenced, but may still be cached by the
+    /// MMU. They are deallocated after the TLB is flushed.
+    freed: ArrayVec<PhysAddr, MAX_FREED_TABLES>,
 }
 
 impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Deref for PageTable64Mut<'_, M, PTE, H> {
@@ -186,18 +449,227 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
         Self {
             inner,
             flush: ToFlush::None,
+            freed: ArrayVec::new(),
+        }
+    }
+
+    /// Deallocates the table at `paddr` once the TLB has been flushed.
+    ///
+    /// Unlinking a table changes the translation of its whole range, so the
+    /// entire TLB is flushed.
+    fn free_table(&mut self, paddr: PhysAddr) {
+        self.flush = ToFlush::Full;
+        if self.freed.is_full() {
+            self.commit();
+        }
+        self.freed.push(paddr);
+    }
+
+    /// Flushes the entire TLB right away, and deallocates the freed tables.
+    fn flush_all_now(&mut self) {
+        self.flush = ToFlush::Full;
+        self.commit();
+    }
+
+    /// Whether the root entry at `index` is borrowed from another page table
+    /// by [`PageTable64Mut::copy_from`].
+    #[allow(unused_variables)]
+    fn is_borrowed(&self, index: usize) -> bool {
+        #[cfg(feature = "copy-from")]
+        if index < MAX_ROOT_ENTRIES && self.inner.borrowed_entries.get(index) {
+            return true;
+        }
+        false
+    }
+
+    /// Frees the empty tables below `table` at `level` in the range
+    /// `[start, last]`, and returns whether `table` is empty afterwards.
+    fn compact_table(
+        &mut self,
+        table: &mut [PTE],
+        level: usize,
+        start: usize,
+        last: usize,
+    ) -> bool {
+        let span = 1usize << level_shift::<M>(level);
+        let mut vaddr = start;
+        loop {
+            let index = level_index::<M>(vaddr, level);
+            let entry_last = vaddr.align_down(span).wrapping_add(span - 1);
+            let entry = &mut table[index];
+            if level < M::LEVELS - 1
+                && !(level == 0 && self.is_borrowed(index))
+                && self.next_table(entry).is_some()
+            {
+                let paddr = entry.paddr();
+                let next = self.table_of_mut(paddr);
+                if self.compact_table(next, level + 1, vaddr, last.min(entry_last)) {
+                    entry.clear();
+                    self.free_table(paddr);
+                }
+            }
+            if entry_last >= last {
+                break;
+            }
+            vaddr = entry_last + 1;
+        }
+        table.iter().all(|entry| entry.is_unused())
+    }
+
+    /// Copies the entries of `src` at `level` in the range `[start, last]` to
+    /// `dst` of the `child` page table, see [`PageTable64::fork_cow`].
+    fn fork_table(
+        &mut self,
+        child: &mut Self,
+        src: &mut [PTE],
+        dst: &mut [PTE],
+        level: usize,
+        start: usize,
+        last: usize,
+    ) -> PagingResult {
+        let span = 1usize << level_shift::<M>(level);
+        let mut vaddr = start;
+        loop {
+            let index = level_index::<M>(vaddr, level);
+            let entry_start = vaddr.align_down(span);
+            let entry_last = entry_start.wrapping_add(span - 1);
+            let entry = &mut src[index];
+            if level == 0 && self.is_borrowed(index) {
+                // the borrowed tables are shared, not copied
+                #[cfg(feature = "copy-from")]
+                child.inner.borrowed_entries.set(index, true);
+                dst[index] = *entry;
+            } else if level < M::LEVELS - 1 && self.next_table(entry).is_some() {
+                let table_paddr = child.inner.alloc_table()?;
+                dst[index] = GenericPTE::new_table(table_paddr);
+                let (next_src, next_dst) = (
+                    self.table_of_mut(entry.paddr()),
+                    child.table_of_mut(table_paddr),
+                );
+                self.fork_table(
+                    child,
+                    next_src,
+                    next_dst,
+                    level + 1,
+                    vaddr,
+                    last.min(entry_last),
+                )?;
+            } else if entry.is_present() {
+                let flags = entry.flags();
+                if flags.contains(MappingFlags::WRITE | MappingFlags::USER) {
+                    entry.set_flags(flags - MappingFlags::WRITE, level < M::LEVELS - 1);
+                    entry.set_cow(true);
+                    self.flush(entry_start.into(), span);
+                }
+                dst[index] = *entry;
+            } else if !entry.is_unused() {
+                dst[index] = *entry;
+            }
+            if entry_last >= last {
+                break;
+            }
+            vaddr = entry_last + 1;
         }
+        Ok(())
     }
 
-    fn flush(&mut self, vaddr: M::VirtAddr) {
+    /// Collapses the tables below `table` at `level` in the range
+    /// `[start, last]` into huge pages, from the bottom up.
+    fn collapse_table(&mut self, table: &mut [PTE], level: usize, start: usize, last: usize) {
+        let span = 1usize << level_shift::<M>(level);
+        let mut vaddr = start;
+        loop {
+            let index = level_index::<M>(vaddr, level);
+            let entry_start = vaddr.align_down(span);
+            let entry_last = entry_start.wrapping_add(span - 1);
+            let entry = &mut table[index];
+            if level < M::LEVELS - 1
+                && !(level == 0 && self.is_borrowed(index))
+                && self.next_table(entry).is_some()
+            {
+                let table_paddr = entry.paddr();
+                let next = self.table_of_mut(table_paddr);
+                self.collapse_table(next, level + 1, vaddr, last.min(entry_last));
+                let block = match level_page_size::<M>(level) {
+                    Some(page_size) if entry_start >= start && entry_last <= last => {
+                        Self::uniform_block(next, level + 1, page_size)
+                    }
+                    _ => None,
+                };
+                if let Some(block) = block {
+                    if M::BREAK_BEFORE_MAKE {
+                        entry.clear();
+                        self.flush_all_now();
+                    }
+                    *entry = block;
+                    self.free_table(table_paddr);
+                }
+            }
+            if entry_last >= last {
+                break;
+            }
+            vaddr = entry_last + 1;
+        }
+    }
+
+    /// Returns the huge page entry of the block of `page_size` that `table`
+    /// at `level` maps, if all its entries are present leaves that map it
+    /// contiguously with the same flags, copy-on-write mark and software bits.
+    ///
+    /// The block is accessed or dirty if any of the entries is. It is built
+    /// from an entry that has both states of the block, and there is none if
+    /// one entry is only accessed and another only dirty.
+    fn uniform_block(table: &[PTE], level: usize, page_size: PageSize) -> Option<PTE> {
+        let is_leaf =
+            |entry: &PTE| entry.is_present() && (level == M::LEVELS - 1 || entry.is_huge());
+        let first = &table[0];
+        let (paddr, flags) = (first.paddr(), first.flags());
+        if !is_leaf(first) || !paddr.is_aligned(page_size) {
+            return None;
+        }
+        let state = table
+            .iter()
+            .find(|entry| entry.is_dirty())
+            .or_else(|| table.iter().find(|entry| entry.is_accessed()))
+            .unwrap_or(first);
+        let entry_size = 1usize << level_shift::<M>(level);
+        let uniform = table.iter().enumerate().all(|(i, entry)| {
+            is_leaf(entry)
+                && entry.paddr() == paddr.add(i * entry_size)
+                && entry.flags() == flags
+                && entry.is_cow() == first.is_cow()
+                && entry.sw_bits() == first.sw_bits()
+                && (state.is_accessed() || !entry.is_accessed())
+                && (state.is_dirty() || !entry.is_dirty())
+        });
+        uniform.then(|| {
+            let mut block = *state;
+            block.set_flags(flags, true);
+            block.set_paddr(paddr);
+            block
+        })
+    }
+
+    /// Records the TLB entry of the page of `size` bytes at `vaddr` to be
+    /// flushed on the next commit.
+    fn flush(&mut self, vaddr: M::VirtAddr, size: usize) {
+        let start = vaddr.into().align_down(size);
+        let end = start.wrapping_add(size);
+        let range = TlbRange {
+            start,
+            end,
+            stride: size,
+        };
         match self.flush {
             ToFlush::None => {
-                let mut addresses = ArrayVec::new();
-                addresses.push(vaddr);
-                self.flush = ToFlush::Addresses(addresses);
+                let mut ranges = ArrayVec::new();
+                ranges.push(range);
+                self.flush = ToFlush::Ranges(ranges);
             }
-            ToFlush::Addresses(ref mut addrs) => {
-                if addrs.try_push(vaddr).is_err() {
+            ToFlush::Ranges(ref mut ranges) => {
+                if !ranges.iter_mut().any(|r| r.try_merge(start, end))
+                    && ranges.try_push(range).is_err()
+                {
                     self.flush = ToFlush::Full;
                 }
             }
@@ -205,87 +677,80 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
         }
     }
 
+    fn root_table_mut(&mut self) -> &'a mut [PTE] {
+        let ptr = self.handler.phys_to_virt(self.root_paddr()).as_mut_ptr() as _;
+        unsafe { core::slice::from_raw_parts_mut(ptr, root_entry_count::<M>()) }
+    }
+
     fn table_of_mut(&mut self, paddr: PhysAddr) -> &'a mut [PTE] {
-        let ptr = H::phys_to_virt(paddr).as_mut_ptr() as _;
-        unsafe { core::slice::from_raw_parts_mut(ptr, ENTRY_COUNT) }
+        let ptr = self.handler.phys_to_virt(paddr).as_mut_ptr() as _;
+        unsafe { core::slice::from_raw_parts_mut(ptr, entry_count::<M>()) }
     }
 
-    fn next_table_mut(&mut self, entry: &PTE) -> PagingResult<&'a mut [PTE]> {
-        if entry.paddr().as_usize() == 0 {
-            Err(PagingError::NotMapped)
-        } else if entry.is_huge() {
-            Err(PagingError::MappedToHugePage)
+    fn next_table_mut(&mut self, entry: &PTE) -> Option<&'a mut [PTE]> {
+        if entry.paddr().as_usize() == 0 || entry.is_huge() {
+            None
         } else {
-            Ok(self.table_of_mut(entry.paddr()))
+            Some(self.table_of_mut(entry.paddr()))
         }
     }
 
-    fn next_table_mut_or_create(&mut self, entry: &mut PTE) -> PagingResult<&'a mut [PTE]> {
+    fn next_table_mut_or_create(
+        &mut self,
+        entry: &mut PTE,
+        vaddr: usize,
+        level: usize,
+    ) -> PagingResult<&'a mut [PTE]> {
         if entry.is_unused() {
-            let paddr = PageTable64::<M, PTE, H>::alloc_table()?;
+            let paddr = self.inner.alloc_table()?;
             *entry = GenericPTE::new_table(paddr);
             Ok(self.table_of_mut(paddr))
         } else {
             self.next_table_mut(entry)
+                .ok_or_else(|| walk_error(entry, vaddr, level))
         }
     }
 
     fn get_entry_mut(&mut self, vaddr: M::VirtAddr) -> PagingResult<(&mut PTE, PageSize)> {
         let vaddr: usize = vaddr.into();
-        let p3 = if M::LEVELS == 3 {
-            self.table_of_mut(self.root_paddr())
-        } else if M::LEVELS == 4 {
-            let p4 = self.table_of_mut(self.root_paddr());
-            let p4e = &mut p4[p4_index(vaddr)];
-            self.next_table_mut(p4e)?
-        } else {
-            unreachable!()
-        };
-        let p3e = &mut p3[p3_index(vaddr)];
-        if p3e.is_huge() {
-            return Ok((p3e, PageSize::Size1G));
-        }
-
-        let p2 = self.next_table_mut(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
-        if p2e.is_huge() {
-            return Ok((p2e, PageSize::Size2M));
+        let mut table = self.root_table_mut();
+        for level in 0..M::LEVELS - 1 {
+            let entry = &mut table[level_index::<M>(vaddr, level)];
+            if entry.is_huge() {
+                if let Some(size) = level_page_size::<M>(level) {
+                    return Ok((entry, size));
+                }
+            }
+            table = self
+                .next_table_mut(entry)
+                .ok_or_else(|| walk_error(entry, vaddr, level))?;
         }
-
-        let p1 = self.next_table_mut(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok((p1e, PageSize::Size4K))
+        let entry = &mut table[level_index::<M>(vaddr, M::LEVELS - 1)];
+        Ok((entry, base_page_size::<M>()))
     }
 
     fn get_entry_mut_or_create(
         &mut self,
         vaddr: M::VirtAddr,
         page_size: PageSize,
-    ) -> PagingResult<&mut PTE> {
+    ) -> PagingResult<(&mut PTE, usize)> {
         let vaddr: usize = vaddr.into();
-        let p3 = if M::LEVELS == 3 {
-            self.table_of_mut(self.root_paddr())
-        } else if M::LEVELS == 4 {
-            let p4 = self.table_of_mut(self.root_paddr());
-            let p4e = &mut p4[p4_index(vaddr)];
-            self.next_table_mut_or_create(p4e)?
-        } else {
-            unreachable!()
-        };
-        let p3e = &mut p3[p3_index(vaddr)];
-        if page_size == PageSize::Size1G {
-            return Ok(p3e);
-        }
-
-        let p2 = self.next_table_mut_or_create(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
-        if page_size == PageSize::Size2M {
-            return Ok(p2e);
+        let target_level = page_size_level::<M>(page_size)
+            .ok_or(PagingError::UnsupportedPageSize(page_size.into()))?;
+        let mut table = self.root_table_mut();
+        for level in 0..target_level {
+            let entry = &mut table[level_index::<M>(vaddr, level)];
+            table = self.next_table_mut_or_create(entry, vaddr, level)?;
         }
+        Ok((
+            &mut table[level_index::<M>(vaddr, target_level)],
+            target_level,
+        ))
+    }
 
-        let p1 = self.next_table_mut_or_create(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok(p1e)
+    /// Creates a [`Cursor`] at `vaddr` to walk and edit this page table.
+    pub fn cursor(&mut self, vaddr: M::VirtAddr) -> Cursor<'_, 'a, M, PTE, H> {
+        Cursor::new(self, vaddr)
     }
 
     /// Maps a virtual page to a physical frame with the given `page_size`
@@ -296,7 +761,12 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
     /// aligned down automatically.
     ///
     /// Returns [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped)
-    /// if the mapping is already present.
+    /// if the mapping is already present,
+    /// [`Err(PagingError::InvalidVirtAddr)`](PagingError::InvalidVirtAddr) or
+    /// [`Err(PagingError::InvalidPhysAddr)`](PagingError::InvalidPhysAddr) if
+    /// an address is out of range, or
+    /// [`Err(PagingError::UnsupportedPageSize)`](PagingError::UnsupportedPageSize)
+    /// if no level of this page table maps pages of `page_size`.
     pub fn map(
         &mut self,
         vaddr: M::VirtAddr,
@@ -304,12 +774,21 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
         page_size: PageSize,
         flags: MappingFlags,
     ) -> PagingResult {
-        let entry = self.get_entry_mut_or_create(vaddr, page_size)?;
+        check_addrs::<M>(vaddr.into(), target)?;
+        let (entry, level) = self.get_entry_mut_or_create(vaddr, page_size)?;
         if !entry.is_unused() {
-            return Err(PagingError::AlreadyMapped);
+            return Err(PagingError::AlreadyMapped {
+                vaddr: vaddr.into(),
+                level,
+                entry: entry.bits(),
+            });
         }
-        *entry = GenericPTE::new_page(target.align_down(page_size), flags, page_size.is_huge());
-        self.flush(vaddr);
+        *entry = GenericPTE::new_page(
+            target.align_down(page_size),
+            flags,
+            is_huge_page::<M>(page_size),
+        );
+        self.flush(vaddr, page_size.into());
         Ok(())
     }
 
@@ -328,57 +807,160 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
     ) -> PagingResult<PageSize> {
         let (entry, size) = self.get_entry_mut(vaddr)?;
         entry.set_paddr(paddr);
-        entry.set_flags(flags, size.is_huge());
-        self.flush(vaddr);
+        entry.set_flags(flags, is_huge_page::<M>(size));
+        self.flush(vaddr, size.into());
         Ok(size)
     }
 
     /// Updates the flags of the mapping starts with `vaddr`.
     ///
-    /// Returns the page size of the mapping.
+    /// The bits that [`MappingFlags`] do not control, such as the software
+    /// bits, are kept. Returns the page size of the mapping.
     ///
     /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
     /// mapping is not present.
     pub fn protect(&mut self, vaddr: M::VirtAddr, flags: MappingFlags) -> PagingResult<PageSize> {
         let (entry, size) = self.get_entry_mut(vaddr)?;
         if !entry.is_present() {
-            return Err(PagingError::NotMapped);
+            return Err(not_mapped::<M>(vaddr.into(), size));
+        }
+        entry.set_flags(flags, is_huge_page::<M>(size));
+        self.flush(vaddr, size.into());
+        Ok(size)
+    }
+
+    /// Sets the software bits of the mapping starts with `vaddr`, see
+    /// [`GenericPTE::set_sw_bits`].
+    ///
+    /// The hardware ignores these bits, so the TLB is not flushed. Returns the
+    /// page size of the mapping, or
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if it is not
+    /// present.
+    pub fn set_sw_bits(&mut self, vaddr: M::VirtAddr, bits: usize) -> PagingResult<PageSize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            return Err(not_mapped::<M>(vaddr.into(), size));
         }
-        entry.set_flags(flags, size.is_huge());
-        self.flush(vaddr);
+        entry.set_sw_bits(bits);
         Ok(size)
     }
 
     /// Unmaps the mapping starts with `vaddr`.
     ///
+    /// The intermediate tables are kept even if they become empty, see
+    /// [`PageTable64Mut::compact`].
+    ///
     /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
-    /// mapping is not present.
+    /// mapping is not present. A swap entry is kept, and
+    /// [`Err(PagingError::Swapped)`](PagingError::Swapped) is returned.
     pub fn unmap(
         &mut self,
         vaddr: M::VirtAddr,
     ) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
         let (entry, size) = self.get_entry_mut(vaddr)?;
         if !entry.is_present() {
-            entry.clear();
-            return Err(PagingError::NotMapped);
+            let err = not_present::<M, _>(entry, vaddr.into(), size);
+            if matches!(err, PagingError::NotMapped { .. }) {
+                entry.clear();
+            }
+            return Err(err);
         }
         let paddr = entry.paddr();
         let flags = entry.flags();
         entry.clear();
-        self.flush(vaddr);
+        self.flush(vaddr, size.into());
         Ok((paddr, flags, size))
     }
 
+    /// Sets the entry of the base page at `vaddr` to a swap entry that holds
+    /// `payload`, see [`GenericPTE::new_swap`].
+    ///
+    /// Missing intermediate tables are created. The entry is kept by
+    /// [`unmap`](Self::unmap), and is reported by [`PageTable64::query`], until
+    /// it is removed by [`take_swap`](Self::take_swap).
+    ///
+    /// Returns [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped)
+    /// if the entry is in use, unless it is a swap entry already, or
+    /// [`Err(PagingError::InvalidSwapPayload)`](PagingError::InvalidSwapPayload)
+    /// if `payload` does not fit in [`GenericPTE::SWAP_PAYLOAD_BITS`] bits, or
+    /// the entries do not support swap entries at all.
+    pub fn set_swap(&mut self, vaddr: M::VirtAddr, payload: usize) -> PagingResult {
+        if PTE::SWAP_PAYLOAD_BITS == 0
+            || payload.checked_shr(PTE::SWAP_PAYLOAD_BITS).unwrap_or(0) != 0
+        {
+            return Err(PagingError::InvalidSwapPayload(payload));
+        }
+        let (entry, level) = self.get_entry_mut_or_create(vaddr, base_page_size::<M>())?;
+        if !entry.is_unused() && entry.swap_payload().is_none() {
+            return Err(PagingError::AlreadyMapped {
+                vaddr: vaddr.into(),
+                level,
+                entry: entry.bits(),
+            });
+        }
+        // not present before and after, so nothing to flush
+        *entry = GenericPTE::new_swap(payload);
+        Ok(())
+    }
+
+    /// Removes the swap entry at `vaddr`, set by [`set_swap`](Self::set_swap),
+    /// and returns its payload.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if it is
+    /// not a swap entry.
+    pub fn take_swap(&mut self, vaddr: M::VirtAddr) -> PagingResult<usize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        let payload = entry
+            .swap_payload()
+            .ok_or_else(|| not_mapped::<M>(vaddr.into(), size))?;
+        entry.clear();
+        Ok(payload)
+    }
+
+    /// Resolves a write fault at `vaddr` on a copy-on-write mapping, created by
+    /// [`PageTable64::fork_cow`].
+    ///
+    /// `copy` is called with the physical frame and the page size of the
+    /// mapping, and returns the frame to map writable instead: a copy of it,
+    /// or the frame itself if it is no longer shared. Returns
+    /// [`Err(PagingError::NoMemory)`](PagingError::NoMemory) if it returns
+    /// [`None`].
+    ///
+    /// Returns `Ok(false)` if the mapping is not copy-on-write, or
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if it is not
+    /// present.
+    pub fn resolve_cow(
+        &mut self,
+        vaddr: M::VirtAddr,
+        copy: impl FnOnce(PhysAddr, PageSize) -> Option<PhysAddr>,
+    ) -> PagingResult<bool> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            return Err(not_mapped::<M>(vaddr.into(), size));
+        }
+        if !entry.is_cow() {
+            return Ok(false);
+        }
+        let paddr = copy(entry.paddr(), size).ok_or(PagingError::NoMemory)?;
+        let flags = entry.flags() | MappingFlags::WRITE;
+        entry.set_paddr(paddr);
+        entry.set_flags(flags, is_huge_page::<M>(size));
+        entry.set_cow(false);
+        self.flush(vaddr, size.into());
+        Ok(true)
+    }
+
     /// Maps a contiguous virtual memory region to a contiguous physical memory
     /// region with the given mapping `flags`.
     ///
     /// The virtual and physical memory regions start with `vaddr` and `paddr`
     /// respectively. The region size is `size`. The addresses and `size` must
-    /// be aligned to 4K, otherwise it will return
+    /// be aligned to the base page size (4K unless [`PagingMetaData::PAGE_SHIFT`]
+    /// says otherwise), otherwise it will return
     /// [`Err(PagingError::NotAligned)`].
     ///
     /// When `allow_huge` is true, it will try to map the region with huge pages
-    /// if possible. Otherwise, it will map the region with 4K pages.
+    /// if possible. Otherwise, it will map the region with base pages.
     ///
     /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
     pub fn map_region(
@@ -391,8 +973,9 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
     ) -> PagingResult {
         let mut vaddr_usize: usize = vaddr.into();
         let mut size = size;
-        if !PageSize::Size4K.is_aligned(vaddr_usize) || !PageSize::Size4K.is_aligned(size) {
-            return Err(PagingError::NotAligned);
+        let base_size = base_page_size::<M>();
+        if !base_size.is_aligned(vaddr_usize) || !base_size.is_aligned(size) {
+            return Err(PagingError::NotAligned { vaddr: vaddr_usize });
         }
         trace!(
             "map_region({:#x}): [{:#x}, {:#x}) {:?}",
@@ -401,30 +984,27 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
             vaddr_usize + size,
             flags,
         );
+        let mut cursor = self.cursor(vaddr);
         while size > 0 {
-            let vaddr = vaddr_usize.into();
-            let paddr = get_paddr(vaddr);
+            let paddr = get_paddr(cursor.vaddr());
             let page_size = if allow_huge {
-                if PageSize::Size1G.is_aligned(vaddr_usize)
-                    && paddr.is_aligned(PageSize::Size1G)
-                    && size >= PageSize::Size1G as usize
-                {
-                    PageSize::Size1G
-                } else if PageSize::Size2M.is_aligned(vaddr_usize)
-                    && paddr.is_aligned(PageSize::Size2M)
-                    && size >= PageSize::Size2M as usize
-                {
-                    PageSize::Size2M
-                } else {
-                    PageSize::Size4K
-                }
+                // levels go from the largest page size to the smallest one
+                (0..M::LEVELS)
+                    .filter_map(level_page_size::<M>)
+                    .find(|&page_size| {
+                        page_size.is_aligned(vaddr_usize)
+                            && paddr.is_aligned(page_size)
+                            && size >= page_size as usize
+                    })
+                    .unwrap_or(base_size)
             } else {
-                PageSize::Size4K
+                base_size
             };
-            self.map(vaddr, paddr, page_size, flags).inspect_err(|e| {
+            cursor.map(paddr, page_size, flags).inspect_err(|e| {
                 error!("failed to map page: {vaddr_usize:#x?}({page_size:?}) -> {paddr:#x?}, {e:?}")
             })?;
 
+            cursor.advance(page_size as usize);
             vaddr_usize += page_size as usize;
             size -= page_size as usize;
         }
@@ -435,9 +1015,13 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
     ///
     /// The region must be mapped before using [`PageTable64::map_region`], or
     /// unexpected behaviors may occur. It can deal with huge pages
-    /// automatically.
+    /// automatically, and splits those that are partly in the region.
+    ///
+    /// The intermediate tables left empty are freed, see
+    /// [`PageTable64Mut::compact`].
     pub fn unmap_region(&mut self, vaddr: M::VirtAddr, size: usize) -> PagingResult {
         let mut vaddr_usize: usize = vaddr.into();
+        let region_size = size;
         let mut size = size;
         trace!(
             "unmap_region({:#x}) [{:#x}, {:#x})",
@@ -445,25 +1029,69 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
             vaddr_usize,
             vaddr_usize + size,
         );
+        let mut cursor = self.cursor(vaddr);
         while size > 0 {
-            let vaddr = vaddr_usize.into();
-            let (_, _, page_size) = self
-                .unmap(vaddr)
+            let (_, _, page_size) = cursor
+                .split_to_fit(size)
+                .and_then(|_| cursor.unmap())
                 .inspect_err(|e| error!("failed to unmap page: {vaddr_usize:#x?}, {e:?}"))?;
 
             assert!(page_size.is_aligned(vaddr_usize));
             assert!(page_size as usize <= size);
+            cursor.advance(page_size as usize);
             vaddr_usize += page_size as usize;
             size -= page_size as usize;
         }
+        self.compact(vaddr, region_size);
         Ok(())
     }
 
+    /// Frees the intermediate tables that map nothing in a virtual memory
+    /// region.
+    ///
+    /// The root table is kept, as well as the tables borrowed from another
+    /// page table by [`PageTable64Mut::copy_from`]. The freed tables are
+    /// deallocated on the next [`commit`](Self::commit), after the TLB is
+    /// flushed.
+    pub fn compact(&mut self, vaddr: M::VirtAddr, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let start: usize = vaddr.into();
+        let root = self.root_table_mut();
+        self.compact_table(root, 0, start, start + (size - 1));
+    }
+
+    /// Collapses the tables in a virtual memory region into huge pages, where
+    /// all their entries map contiguous physical memory with the same flags,
+    /// copy-on-write mark and software bits, which the huge pages keep.
+    ///
+    /// A huge page is accessed or dirty if any of its entries is. The tables
+    /// whose entries have accessed and dirty states that no single entry
+    /// combines are not collapsed.
+    ///
+    /// Only the tables whose whole range is in the region are collapsed. It
+    /// works from the bottom up, so 4K pages can be collapsed into a 2M page,
+    /// and then into a 1G page, if the architecture supports them. The freed
+    /// tables are deallocated on the next [`commit`](Self::commit).
+    ///
+    /// If [`PagingMetaData::BREAK_BEFORE_MAKE`] is set, each range is unmapped
+    /// and the TLB flushed before the huge page is mapped, so concurrent
+    /// accesses to the range may fault.
+    pub fn collapse(&mut self, vaddr: M::VirtAddr, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let start: usize = vaddr.into();
+        let root = self.root_table_mut();
+        self.collapse_table(root, 0, start, start + (size - 1));
+    }
+
     /// Updates mapping flags of a contiguous virtual memory region.
     ///
     /// The region must be mapped before using [`PageTable64::map_region`], or
     /// unexpected behaviors may occur. It can deal with huge pages
-    /// automatically.
+    /// automatically, and splits those that are partly in the region.
     pub fn protect_region(
         &mut self,
         vaddr: M::VirtAddr,
@@ -479,28 +1107,85 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
             vaddr_usize + size,
             flags,
         );
+        let mut cursor = self.cursor(vaddr);
         while size > 0 {
-            let vaddr = vaddr_usize.into();
-            let page_size = match self.protect(vaddr, flags) {
+            let step = match cursor
+                .split_to_fit(size)
+                .and_then(|_| cursor.protect(flags))
+            {
                 Ok(page_size) => {
                     assert!(page_size.is_aligned(vaddr_usize));
                     assert!(page_size as usize <= size);
 
-                    page_size
+                    cursor.advance(page_size as usize);
+                    page_size as usize
                 }
-                Err(PagingError::NotMapped) => PageSize::Size4K,
+                // skip the whole unmapped region
+                Err(PagingError::NotMapped { .. }) => cursor.step(),
                 Err(e) => {
                     error!("failed to protect page: {vaddr_usize:#x?}, {e:?}");
                     return Err(e);
                 }
             };
 
-            vaddr_usize += page_size as usize;
-            size -= page_size as usize;
+            vaddr_usize += step;
+            size = size.saturating_sub(step);
         }
         Ok(())
     }
 
+    /// Clears the dirty state of the mappings in a virtual memory region, and
+    /// calls `f` with the start address, the physical frame and the page size
+    /// of each mapping that was dirty.
+    ///
+    /// Mappings that are partly in the region are handled as a whole. The TLB
+    /// entries are flushed on the next [`commit`](Self::commit), which must be
+    /// done before relying on new writes being tracked. See
+    /// [`GenericPTE::is_dirty`] for architectures without a dirty bit.
+    pub fn harvest_dirty(
+        &mut self,
+        vaddr: M::VirtAddr,
+        size: usize,
+        f: impl FnMut(M::VirtAddr, PhysAddr, PageSize),
+    ) {
+        self.harvest(vaddr, size, PTE::is_dirty, PTE::clear_dirty, f);
+    }
+
+    /// Clears the accessed state of the mappings in a virtual memory region,
+    /// and calls `f` with the start address, the physical frame and the page
+    /// size of each mapping that was accessed.
+    ///
+    /// It works like [`harvest_dirty`](Self::harvest_dirty), but for the
+    /// accessed state. See [`GenericPTE::is_accessed`].
+    pub fn harvest_accessed(
+        &mut self,
+        vaddr: M::VirtAddr,
+        size: usize,
+        f: impl FnMut(M::VirtAddr, PhysAddr, PageSize),
+    ) {
+        self.harvest(vaddr, size, PTE::is_accessed, PTE::clear_accessed, f);
+    }
+
+    fn harvest(
+        &mut self,
+        vaddr: M::VirtAddr,
+        size: usize,
+        test: fn(&PTE) -> bool,
+        clear: fn(&mut PTE),
+        mut f: impl FnMut(M::VirtAddr, PhysAddr, PageSize),
+    ) {
+        let end = vaddr.into().saturating_add(size);
+        self.cursor(vaddr)
+            .for_each_leaf(end, |vaddr, entry, page_size| {
+                if !test(entry) {
+                    return false;
+                }
+                clear(entry);
+                f(vaddr, entry.paddr(), page_size);
+                true
+            });
+    }
+
     /// Copy entries from another page table within the given virtual memory
     /// range.
     #[cfg(feature = "copy-from")]
@@ -508,22 +1193,14 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
         if size == 0 {
             return;
         }
-        let src_table = self.table_of(other.root_paddr);
-        let dst_table = self.table_of_mut(self.root_paddr);
-        let index_fn = if M::LEVELS == 3 {
-            p3_index
-        } else if M::LEVELS == 4 {
-            p4_index
-        } else {
-            unreachable!()
-        };
-        let start_idx = index_fn(start.into());
-        let end_idx = index_fn(start.into() + size - 1) + 1;
-        assert!(start_idx < ENTRY_COUNT);
-        assert!(end_idx <= ENTRY_COUNT);
+        let src_table = other.root_table();
+        let dst_table = self.root_table_mut();
+        let start_idx = level_index::<M>(start.into(), 0);
+        let end_idx = level_index::<M>(start.into() + (size - 1), 0) + 1;
+        assert!(end_idx <= MAX_ROOT_ENTRIES);
         for i in start_idx..end_idx {
             let entry = &mut dst_table[i];
-            if !self.inner.borrowed_entries.set(i, true) && self.next_table(entry).is_ok() {
+            if !self.inner.borrowed_entries.set(i, true) && self.next_table(entry).is_some() {
                 self.dealloc_tree(entry.paddr(), 1);
             }
             *entry = src_table[i];
@@ -533,18 +1210,28 @@ impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> PageTable64Mut<'a
     /// Commits the changes made to the page table, flushing the TLB as
     /// necessary.
     pub fn commit(&mut self) {
+        let asid = self.inner.asid;
         match &self.flush {
             ToFlush::None => {}
-            ToFlush::Addresses(addrs) => {
-                for vaddr in addrs.iter() {
-                    M::flush_tlb(Some(*vaddr));
+            _ if self.inner.detached => {}
+            ToFlush::Ranges(ranges) => {
+                for r in ranges.iter() {
+                    M::flush_tlb_range(r.start.into(), r.end.into(), r.stride, asid);
                 }
+                self.inner.handler.shootdown(asid, TlbFlush::Ranges(ranges));
             }
             ToFlush::Full => {
-                M::flush_tlb(None);
+                match asid {
+                    Some(asid) => M::flush_tlb_asid(asid, None),
+                    None => M::flush_tlb(None),
+                }
+                self.inner.handler.shootdown(asid, TlbFlush::All);
             }
         }
         self.flush = ToFlush::None;
+        for paddr in self.freed.drain(..) {
+            self.inner.handler.dealloc_frame(paddr);
+        }
     }
 }
 
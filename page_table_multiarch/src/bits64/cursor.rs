@@ -0,0 +1,334 @@
+use memory_addr::{MemoryAddr, PhysAddr};
+
+use super::{
+    PageTable64Mut, base_page_size, check_addrs, is_huge_page, level_index, level_page_size,
+    level_shift, not_mapped, not_present, page_size_level, walk_error,
+};
+use crate::{
+    GenericPTE, MappingFlags, PageSize, PagingError, PagingHandler, PagingMetaData, PagingResult,
+};
+
+/// The maximum number of levels a [`Cursor`] can keep track of.
+const MAX_LEVELS: usize = 5;
+
+/// A cursor to walk and edit a page table at a virtual address.
+///
+/// It keeps the tables on the path from the root to the current position, so
+/// moving forward only walks the levels that change. Created by
+/// [`PageTable64Mut::cursor`].
+///
+/// TLB flushes are collected by the [`PageTable64Mut`] the cursor borrows.
+pub struct Cursor<'a, 'b, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> {
+    pt: &'a mut PageTable64Mut<'b, M, PTE, H>,
+    vaddr: usize,
+    /// `path[level]` is the physical address of the table at `level` for
+    /// `vaddr`, for every level below `depth`.
+    path: [PhysAddr; MAX_LEVELS],
+    depth: usize,
+}
+
+impl<'a, 'b, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Cursor<'a, 'b, M, PTE, H> {
+    pub(super) fn new(pt: &'a mut PageTable64Mut<'b, M, PTE, H>, vaddr: M::VirtAddr) -> Self {
+        assert!(M::LEVELS <= MAX_LEVELS, "too many page table levels");
+        let mut path = [PhysAddr::from_usize(0); MAX_LEVELS];
+        path[0] = pt.root_paddr();
+        Self {
+            pt,
+            vaddr: vaddr.into(),
+            path,
+            depth: 1,
+        }
+    }
+
+    /// Returns the virtual address of the current position.
+    pub fn vaddr(&self) -> M::VirtAddr {
+        self.vaddr.into()
+    }
+
+    /// Moves the cursor to `vaddr`.
+    ///
+    /// The tables on the path that `vaddr` shares with the current position
+    /// are kept.
+    pub fn seek(&mut self, vaddr: M::VirtAddr) {
+        let vaddr: usize = vaddr.into();
+        // the table at `depth` is the same if the entries above it are
+        let mut depth = 1;
+        while depth < self.depth && (self.vaddr ^ vaddr) >> level_shift::<M>(depth - 1) == 0 {
+            depth += 1;
+        }
+        self.vaddr = vaddr;
+        self.depth = depth;
+    }
+
+    /// Moves the cursor forward by `size` bytes.
+    pub fn advance(&mut self, size: usize) {
+        self.seek(self.vaddr.wrapping_add(size).into());
+    }
+
+    /// Moves the cursor forward to the end of the current mapping, or to the
+    /// end of the unmapped region covered by the entry where the walk stops.
+    ///
+    /// Empty subtrees are skipped as a whole. Returns the distance moved.
+    pub fn step(&mut self) -> usize {
+        let level = self.walk(M::LEVELS - 1);
+        let size = 1 << level_shift::<M>(level);
+        let next = self.vaddr.align_down(size).wrapping_add(size);
+        let distance = next.wrapping_sub(self.vaddr);
+        self.seek(next.into());
+        distance
+    }
+
+    /// Queries the mapping at the current position.
+    ///
+    /// Returns the physical address that the current position maps to, the
+    /// mapping flags, and the page size.
+    ///
+    /// Returns an error if the mapping is not present, see
+    /// [`PageTable64::query`](super::PageTable64::query).
+    pub fn query(&mut self) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        let vaddr = self.vaddr;
+        let (entry, size) = self.leaf()?;
+        if !entry.is_present() {
+            return Err(not_present::<M, _>(entry, vaddr, size));
+        }
+        Ok((
+            entry.paddr().add(size.align_offset(vaddr)),
+            entry.flags(),
+            size,
+        ))
+    }
+
+    /// Maps the page of `page_size` at the current position to the physical
+    /// frame starting with `target`, with the given mapping `flags`.
+    ///
+    /// Missing intermediate tables are created. See [`PageTable64Mut::map`].
+    pub fn map(
+        &mut self,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        check_addrs::<M>(self.vaddr, target)?;
+        let level = page_size_level::<M>(page_size)
+            .ok_or(PagingError::UnsupportedPageSize(page_size.into()))?;
+        self.walk_create(level)?;
+        let vaddr = self.vaddr;
+        let entry = self.entry_mut(level);
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped {
+                vaddr,
+                level,
+                entry: entry.bits(),
+            });
+        }
+        *entry = GenericPTE::new_page(
+            target.align_down(page_size),
+            flags,
+            is_huge_page::<M>(page_size),
+        );
+        self.pt.flush(self.vaddr.into(), page_size.into());
+        Ok(())
+    }
+
+    /// Updates the flags of the mapping at the current position.
+    ///
+    /// Returns the page size of the mapping. See [`PageTable64Mut::protect`].
+    pub fn protect(&mut self, flags: MappingFlags) -> PagingResult<PageSize> {
+        let vaddr = self.vaddr;
+        let (entry, size) = self.leaf()?;
+        if !entry.is_present() {
+            return Err(not_mapped::<M>(vaddr, size));
+        }
+        entry.set_flags(flags, is_huge_page::<M>(size));
+        self.pt.flush(self.vaddr.into(), size.into());
+        Ok(size)
+    }
+
+    /// Splits the huge page at the current position into pages of the next
+    /// level, which map the same physical frames with the same flags, and
+    /// keep the copy-on-write mark and the software bits.
+    ///
+    /// Returns the new page size. Returns
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the mapping
+    /// is not present, [`Err(PagingError::NotAligned)`](PagingError::NotAligned)
+    /// if it is not a huge page,
+    /// [`Err(PagingError::UnsupportedPageSize)`](PagingError::UnsupportedPageSize)
+    /// if the next level has no page size, or
+    /// [`Err(PagingError::NoMemory)`](PagingError::NoMemory) if the new table
+    /// cannot be allocated.
+    pub fn split(&mut self) -> PagingResult<PageSize> {
+        let vaddr = self.vaddr;
+        let (entry, size) = self.leaf()?;
+        if !entry.is_present() {
+            return Err(not_mapped::<M>(vaddr, size));
+        }
+        let parent = *entry;
+        let (paddr, flags) = (entry.paddr(), entry.flags());
+        if !is_huge_page::<M>(size) {
+            return Err(PagingError::NotAligned { vaddr });
+        }
+        let level = page_size_level::<M>(size).unwrap();
+        let next_size = level_page_size::<M>(level + 1).ok_or(PagingError::UnsupportedPageSize(
+            1 << level_shift::<M>(level + 1),
+        ))?;
+
+        let table_paddr = self.pt.inner.alloc_table()?;
+        let next_huge = is_huge_page::<M>(next_size);
+        for (i, entry) in self.pt.table_of_mut(table_paddr).iter_mut().enumerate() {
+            *entry = parent;
+            entry.set_flags(flags, next_huge);
+            entry.set_paddr(paddr.add(i * next_size as usize));
+        }
+        if M::BREAK_BEFORE_MAKE {
+            self.entry_mut(level).clear();
+            self.pt.flush_all_now();
+        }
+        *self.entry_mut(level) = GenericPTE::new_table(table_paddr);
+        self.path[level + 1] = table_paddr;
+        self.depth = level + 2;
+        // the old block may be cached for the whole range
+        self.pt.flush(vaddr.into(), size.into());
+        Ok(next_size)
+    }
+
+    /// Moves forward to `end`, and calls `f` with the start address, the
+    /// entry and the page size of each present mapping on the way.
+    ///
+    /// `f` returns whether it has changed the entry, so that the TLB entry is
+    /// flushed.
+    pub(super) fn for_each_leaf(
+        &mut self,
+        end: usize,
+        mut f: impl FnMut(M::VirtAddr, &mut PTE, PageSize) -> bool,
+    ) {
+        while self.vaddr < end {
+            let start = self.vaddr;
+            match self.leaf() {
+                Ok((entry, size)) => {
+                    let vaddr = start.align_down(size);
+                    if entry.is_present() && f(vaddr.into(), entry, size) {
+                        self.pt.flush(vaddr.into(), size.into());
+                    }
+                    self.seek(vaddr.wrapping_add(size as usize).into());
+                }
+                Err(_) => {
+                    self.step();
+                }
+            }
+            if self.vaddr <= start {
+                break; // wrapped around
+            }
+        }
+    }
+
+    /// Splits the huge page at the current position until it starts there
+    /// and is not larger than `size`, so that it can be changed as a whole.
+    ///
+    /// Does nothing if the mapping is not present.
+    pub(super) fn split_to_fit(&mut self, size: usize) -> PagingResult {
+        while let Ok((_, _, page_size)) = self.query() {
+            if page_size.is_aligned(self.vaddr) && page_size as usize <= size {
+                break;
+            }
+            self.split()?;
+        }
+        Ok(())
+    }
+
+    /// Unmaps the mapping at the current position.
+    ///
+    /// See [`PageTable64Mut::unmap`].
+    pub fn unmap(&mut self) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        let vaddr = self.vaddr;
+        let (entry, size) = self.leaf()?;
+        if !entry.is_present() {
+            let err = not_present::<M, _>(entry, vaddr, size);
+            if matches!(err, PagingError::NotMapped { .. }) {
+                entry.clear();
+            }
+            return Err(err);
+        }
+        let paddr = entry.paddr();
+        let flags = entry.flags();
+        entry.clear();
+        self.pt.flush(self.vaddr.into(), size.into());
+        Ok((paddr, flags, size))
+    }
+}
+
+// Private implements.
+impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Cursor<'_, '_, M, PTE, H> {
+    /// Returns the table at `level`, which must be on the known path.
+    fn table_mut(&mut self, level: usize) -> &mut [PTE] {
+        debug_assert!(level < self.depth);
+        if level == 0 {
+            self.pt.root_table_mut()
+        } else {
+            self.pt.table_of_mut(self.path[level])
+        }
+    }
+
+    fn entry_mut(&mut self, level: usize) -> &mut PTE {
+        let index = level_index::<M>(self.vaddr, level);
+        &mut self.table_mut(level)[index]
+    }
+
+    /// Returns the next level table of `entry`, like
+    /// `PageTable64::next_table`.
+    fn next_table_paddr(entry: &PTE) -> Option<PhysAddr> {
+        if entry.paddr().as_usize() == 0 || entry.is_huge() {
+            None
+        } else {
+            Some(entry.paddr())
+        }
+    }
+
+    /// Extends the known path down to `target_level`, and returns the level
+    /// where it stops if an entry above does not point to a next level table.
+    fn walk(&mut self, target_level: usize) -> usize {
+        while self.depth <= target_level {
+            let level = self.depth - 1;
+            match Self::next_table_paddr(self.entry_mut(level)) {
+                Some(paddr) => {
+                    self.path[self.depth] = paddr;
+                    self.depth += 1;
+                }
+                None => return level,
+            }
+        }
+        target_level
+    }
+
+    /// Extends the known path down to `target_level`, creating missing tables.
+    fn walk_create(&mut self, target_level: usize) -> PagingResult {
+        let vaddr = self.vaddr;
+        while self.depth <= target_level {
+            let level = self.depth - 1;
+            let entry = self.entry_mut(level);
+            let paddr = if entry.is_unused() {
+                let paddr = self.pt.inner.alloc_table()?;
+                *self.entry_mut(level) = GenericPTE::new_table(paddr);
+                paddr
+            } else {
+                Self::next_table_paddr(entry).ok_or_else(|| walk_error(entry, vaddr, level))?
+            };
+            self.path[self.depth] = paddr;
+            self.depth += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the leaf entry at the current position and its page size.
+    fn leaf(&mut self) -> PagingResult<(&mut PTE, PageSize)> {
+        let level = self.walk(M::LEVELS - 1);
+        if level == M::LEVELS - 1 {
+            return Ok((self.entry_mut(level), base_page_size::<M>()));
+        }
+        let vaddr = self.vaddr;
+        let entry = self.entry_mut(level);
+        match level_page_size::<M>(level) {
+            Some(size) if entry.is_huge() => Ok((entry, size)),
+            _ => Err(walk_error(entry, vaddr, level)),
+        }
+    }
+}
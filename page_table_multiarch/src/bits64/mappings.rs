@@ -0,0 +1,178 @@
+use core::ops::Range;
+
+use memory_addr::{MemoryAddr, PhysAddr};
+
+use super::{PageTable64, base_page_size, level_index, level_page_size, level_shift};
+use crate::{GenericPTE, MappingFlags, PageSize, PagingHandler, PagingMetaData};
+
+/// A leaf mapping in the page table, as found by [`Mappings`].
+struct Leaf {
+    vaddr: usize,
+    paddr: PhysAddr,
+    size: usize,
+    flags: MappingFlags,
+    page_size: PageSize,
+}
+
+impl Leaf {
+    /// Whether `next` continues this run, both virtually and physically.
+    fn is_followed_by(&self, next: &Leaf) -> bool {
+        self.vaddr.wrapping_add(self.size) == next.vaddr
+            && self.paddr.wrapping_add(self.size) == next.paddr
+            && self.flags == next.flags
+            && self.page_size == next.page_size
+    }
+}
+
+/// An iterator over the leaf mappings of a page table in a virtual address
+/// range. Created by [`PageTable64::mappings`].
+///
+/// Each item is `(vaddr, paddr, size, flags, page_size)`. Without coalescing,
+/// an item is a single present leaf entry and `size` equals `page_size`.
+/// Leaves that overlap the range are yielded whole, even if they start before
+/// or end after it.
+///
+/// Empty subtrees are skipped as a whole.
+pub struct Mappings<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> {
+    pt: &'a PageTable64<M, PTE, H>,
+    vaddr: usize,
+    end: usize,
+    coalesce: bool,
+    pending: Option<Leaf>,
+}
+
+impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Mappings<'a, M, PTE, H> {
+    pub(super) fn new(pt: &'a PageTable64<M, PTE, H>, range: Range<M::VirtAddr>) -> Self {
+        Self {
+            pt,
+            vaddr: range.start.into(),
+            end: range.end.into(),
+            coalesce: false,
+            pending: None,
+        }
+    }
+
+    /// Merges adjacent leaves that are physically contiguous and have the
+    /// same flags and page size into one run.
+    pub fn coalesce(mut self) -> Self {
+        self.coalesce = true;
+        self
+    }
+
+    /// Moves to the next valid virtual address if `self.vaddr` is not, i.e.,
+    /// jumps over the hole between the lower and upper halves, or over the
+    /// unused bottom of a table that only translates the upper part.
+    ///
+    /// Returns `false` if there is no valid address left.
+    fn skip_invalid(&mut self) -> bool {
+        if M::vaddr_is_valid(self.vaddr) {
+            return true;
+        }
+        // the valid part of the table starts at a root entry, e.g. for the
+        // ARMv7 `TTBR1` table
+        let lower_end = usize::MAX
+            .checked_shl(M::VA_MAX_BITS as u32)
+            .map_or(usize::MAX, |mask| !mask);
+        let root_size = 1 << level_shift::<M>(0);
+        let mut next = self.vaddr;
+        while let Some(vaddr) = (next | (root_size - 1)).checked_add(1) {
+            if vaddr > lower_end {
+                break;
+            }
+            if M::vaddr_is_valid(vaddr) {
+                self.vaddr = vaddr;
+                return true;
+            }
+            next = vaddr;
+        }
+        // the upper half starts with the bits above `VA_MAX_BITS` set if it
+        // has a root of its own, or above `VA_MAX_BITS - 1` if sign-extended
+        let upper = [M::VA_MAX_BITS, M::VA_MAX_BITS - 1]
+            .into_iter()
+            .filter_map(|bits| usize::MAX.checked_shl(bits as u32))
+            .find(|&upper| upper > self.vaddr && M::vaddr_is_valid(upper));
+        match upper {
+            Some(upper) => {
+                self.vaddr = upper;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves to `next`, or stops the iteration if it wraps around.
+    fn advance_to(&mut self, next: usize) {
+        if next > self.vaddr {
+            self.vaddr = next;
+        } else {
+            self.end = self.vaddr;
+        }
+    }
+
+    /// Finds the next present leaf entry.
+    fn next_leaf(&mut self) -> Option<Leaf> {
+        'outer: while self.vaddr < self.end && self.skip_invalid() {
+            let vaddr = self.vaddr;
+            let mut table = self.pt.root_table();
+            for level in 0..M::LEVELS {
+                let entry = &table[level_index::<M>(vaddr, level)];
+                let page_size = if level == M::LEVELS - 1 {
+                    Some(base_page_size::<M>())
+                } else if entry.is_huge() {
+                    level_page_size::<M>(level)
+                } else {
+                    None
+                };
+                let size = 1usize << level_shift::<M>(level);
+                let start = vaddr.align_down(size);
+                if let Some(page_size) = page_size {
+                    self.advance_to(start.wrapping_add(size));
+                    if entry.is_present() {
+                        return Some(Leaf {
+                            vaddr: start,
+                            paddr: entry.paddr(),
+                            size,
+                            flags: entry.flags(),
+                            page_size,
+                        });
+                    }
+                    continue 'outer;
+                }
+                match self.pt.next_table(entry) {
+                    Some(next) => table = next,
+                    None => {
+                        // skip the whole subtree
+                        self.advance_to(start.wrapping_add(size));
+                        continue 'outer;
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> Iterator for Mappings<'_, M, PTE, H> {
+    type Item = (M::VirtAddr, PhysAddr, usize, MappingFlags, PageSize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut run = self.pending.take().or_else(|| self.next_leaf())?;
+        if self.coalesce {
+            while let Some(next) = self.next_leaf() {
+                if run.is_followed_by(&next) {
+                    run.size += next.size;
+                } else {
+                    self.pending = Some(next);
+                    break;
+                }
+            }
+        }
+        Some((
+            run.vaddr.into(),
+            run.paddr,
+            run.size,
+            run.flags,
+            run.page_size,
+        ))
+    }
+}
@@ -0,0 +1,188 @@
+use memory_addr::PhysAddr;
+
+use super::{PageTable64, PageTable64Mut};
+use crate::{
+    GenericPTE, MappingFlags, PageSize, PagingError, PagingHandler, PagingMetaData, PagingResult,
+};
+
+/// Metadata of page tables that translate the lower and upper halves of the
+/// virtual address space from separate roots, i.e., whose
+/// [`PagingMetaData::SPLIT_ROOTS`] is `true`.
+pub trait SplitRootsMetaData: PagingMetaData {}
+
+/// Metadata of page tables that a [`SplitAddressSpace`] can be created with.
+///
+/// It is implemented for all [`SplitRootsMetaData`] types, and with the
+/// `copy-from` feature, for all [`PagingMetaData`] types.
+pub trait SplitSpaceMetaData: PagingMetaData {}
+
+#[cfg(not(feature = "copy-from"))]
+impl<M: SplitRootsMetaData> SplitSpaceMetaData for M {}
+
+#[cfg(feature = "copy-from")]
+impl<M: PagingMetaData> SplitSpaceMetaData for M {}
+
+/// An address space whose lower (user) half is translated by a page table of
+/// its own, and whose upper (kernel) half by a kernel page table shared with
+/// other address spaces.
+///
+/// If the architecture translates the two halves from separate roots (see
+/// [`SplitRootsMetaData`]), the user page table only maps the lower half, and
+/// the kernel page table is installed in its own root register by the kernel.
+/// Otherwise, the root entries of the upper half are copied from the kernel
+/// page table (see `PageTable64Mut::copy_from`), so the kernel mappings below
+/// them are shared. They are copied when the address space is created, and
+/// again by [`activate`](Self::activate), so root entries added to the kernel
+/// page table in the meantime are shared as well. This requires the
+/// `copy-from` feature, without which such address spaces cannot be created.
+///
+/// [`query`](Self::query) looks up the page table of the half that `vaddr`
+/// falls in. The kernel page table is only borrowed for reading, so the upper
+/// half is changed through it directly, not through the address space.
+pub struct SplitAddressSpace<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> {
+    user: PageTable64<M, PTE, H>,
+    kernel: &'a PageTable64<M, PTE, H>,
+}
+
+impl<'a, M: SplitSpaceMetaData, PTE: GenericPTE, H: PagingHandler + Default>
+    SplitAddressSpace<'a, M, PTE, H>
+{
+    /// Creates an address space with an empty user page table that uses the
+    /// default handler, and the shared `kernel` page table.
+    ///
+    /// See [`SplitAddressSpace::try_new_in`].
+    pub fn try_new(kernel: &'a PageTable64<M, PTE, H>) -> PagingResult<Self> {
+        Self::try_new_in(kernel, H::default())
+    }
+}
+
+impl<'a, M: SplitSpaceMetaData, PTE: GenericPTE, H: PagingHandler>
+    SplitAddressSpace<'a, M, PTE, H>
+{
+    /// Creates an address space with an empty user page table that uses
+    /// `handler`, and the shared `kernel` page table, or returns the error.
+    ///
+    /// With a single root, the upper half root entries of `kernel` are copied
+    /// to the user page table.
+    pub fn try_new_in(kernel: &'a PageTable64<M, PTE, H>, handler: H) -> PagingResult<Self> {
+        let mut space = Self {
+            user: PageTable64::try_new_in(handler)?,
+            kernel,
+        };
+        space.share_kernel_half();
+        Ok(space)
+    }
+}
+
+impl<'a, M: PagingMetaData, PTE: GenericPTE, H: PagingHandler> SplitAddressSpace<'a, M, PTE, H> {
+    /// Whether `vaddr` is in the upper (kernel) half of the address space.
+    pub fn is_kernel_vaddr(vaddr: M::VirtAddr) -> bool {
+        let vaddr: usize = vaddr.into();
+        if M::SPLIT_ROOTS {
+            // each root covers `VA_MAX_BITS` bits, with the upper half
+            // sign-extended above them
+            vaddr >> M::VA_MAX_BITS != 0
+        } else {
+            vaddr >> (M::VA_MAX_BITS - 1) != 0
+        }
+    }
+
+    /// Returns the page table of the lower half.
+    pub const fn user(&self) -> &PageTable64<M, PTE, H> {
+        &self.user
+    }
+
+    /// Returns the page table of the lower half mutably, e.g., to change a
+    /// region of it.
+    pub fn user_mut(&mut self) -> &mut PageTable64<M, PTE, H> {
+        &mut self.user
+    }
+
+    /// Returns the shared page table of the upper half.
+    pub const fn kernel(&self) -> &'a PageTable64<M, PTE, H> {
+        self.kernel
+    }
+
+    /// Queries the mapping starts with `vaddr` in the page table of its half,
+    /// see [`PageTable64::query`].
+    pub fn query(&self, vaddr: M::VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        if Self::is_kernel_vaddr(vaddr) {
+            self.kernel.query(vaddr)
+        } else {
+            self.user.query(vaddr)
+        }
+    }
+
+    /// Maps a virtual page in the lower half to a physical frame, see
+    /// [`PageTable64Mut::map`].
+    ///
+    /// Returns
+    /// [`Err(PagingError::InvalidVirtAddr)`](PagingError::InvalidVirtAddr) if
+    /// `vaddr` is in the upper half.
+    pub fn map(
+        &mut self,
+        vaddr: M::VirtAddr,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        self.user_table(vaddr)?.map(vaddr, target, page_size, flags)
+    }
+
+    /// Unmaps the mapping starts with `vaddr` in the lower half, see
+    /// [`PageTable64Mut::unmap`].
+    ///
+    /// Returns
+    /// [`Err(PagingError::InvalidVirtAddr)`](PagingError::InvalidVirtAddr) if
+    /// `vaddr` is in the upper half.
+    pub fn unmap(
+        &mut self,
+        vaddr: M::VirtAddr,
+    ) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        self.user_table(vaddr)?.unmap(vaddr)
+    }
+
+    /// Installs the user page table as the current one of this CPU, see
+    /// [`PageTable64::activate`].
+    ///
+    /// With a single root, the upper half root entries of the kernel page
+    /// table are copied to the user page table again first.
+    ///
+    /// # Safety
+    ///
+    /// With separate roots, the kernel page table must be installed already.
+    /// The address space must map the code and data in use by the current
+    /// CPU.
+    pub unsafe fn activate(&mut self) {
+        self.share_kernel_half();
+        unsafe { self.user.activate() }
+    }
+
+    /// Copies the upper half root entries of the kernel page table to the user
+    /// page table, if they share a single root.
+    fn share_kernel_half(&mut self) {
+        #[cfg(feature = "copy-from")]
+        if !M::SPLIT_ROOTS {
+            let half = 1 << (M::VA_MAX_BITS - 1);
+            // the upper half is sign-extended, unless the address space is
+            // narrower than `usize`
+            let start = if M::vaddr_is_valid(half) {
+                half
+            } else {
+                usize::MAX << (M::VA_MAX_BITS - 1)
+            };
+            self.user
+                .to_mut()
+                .copy_from(self.kernel, start.into(), half);
+        }
+    }
+
+    /// Returns the user page table to change the mapping at `vaddr`, which
+    /// must be in the lower half.
+    fn user_table(&mut self, vaddr: M::VirtAddr) -> PagingResult<PageTable64Mut<'_, M, PTE, H>> {
+        if Self::is_kernel_vaddr(vaddr) {
+            return Err(PagingError::InvalidVirtAddr(vaddr.into()));
+        }
+        Ok(self.user.to_mut())
+    }
+}
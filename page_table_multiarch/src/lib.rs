@@ -1,14 +1,18 @@
 #![cfg_attr(not(test), no_std)]
-#![cfg_attr(doc, feature(doc_auto_cfg))]
+#![cfg_attr(doc, feature(doc_cfg))]
 #![doc = include_str!("../README.md")]
 
 #[macro_use]
 extern crate log;
 
 mod arch;
+mod bits32;
 mod bits64;
 
-use core::fmt::Debug;
+use core::{
+    fmt::{self, Debug},
+    marker::PhantomData,
+};
 
 use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
 #[doc(no_inline)]
@@ -16,25 +20,100 @@ pub use page_table_entry::{GenericPTE, MappingFlags};
 
 pub use self::{
     arch::*,
-    bits64::{PageTable64, PageTable64Mut},
+    bits32::{PageTable32, PageTable32Mut},
+    bits64::{
+        Cursor, Mappings, PageTable64, PageTable64Mut, SplitAddressSpace, SplitRootsMetaData,
+        SplitSpaceMetaData,
+    },
 };
 
 /// The error type for page table operation failures.
-#[derive(Debug, PartialEq, Clone, Copy)]
+///
+/// The errors of a page table walk carry the virtual address being walked, and
+/// the level where the walk stops, where 0 is the root level.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PagingError {
     /// Cannot allocate memory.
     NoMemory,
     /// The address is not aligned to the page size.
-    NotAligned,
+    NotAligned {
+        /// The virtual address of the page.
+        vaddr: usize,
+    },
     /// The mapping is not present.
-    NotMapped,
+    NotMapped {
+        /// The virtual address being walked.
+        vaddr: usize,
+        /// The level of the entry that is not present.
+        level: usize,
+    },
     /// The mapping is already present.
-    AlreadyMapped,
+    AlreadyMapped {
+        /// The virtual address being mapped.
+        vaddr: usize,
+        /// The level of the entry in use.
+        level: usize,
+        /// The raw bits of the entry in use, see [`GenericPTE::bits`].
+        entry: usize,
+    },
     /// The page table entry represents a huge page, but the target physical
     /// frame is 4K in size.
-    MappedToHugePage,
+    MappedToHugePage {
+        /// The virtual address being walked.
+        vaddr: usize,
+        /// The level of the huge page entry.
+        level: usize,
+    },
+    /// The mapping is not present, and the entry holds the payload set by
+    /// [`PageTable64Mut::set_swap`], such as a swap slot.
+    Swapped(usize),
+    /// The swap payload does not fit in [`GenericPTE::SWAP_PAYLOAD_BITS`]
+    /// bits.
+    InvalidSwapPayload(usize),
+    /// The virtual address is not valid for the page table, see
+    /// [`PagingMetaData::vaddr_is_valid`].
+    InvalidVirtAddr(usize),
+    /// The physical address is not valid for the page table, see
+    /// [`PagingMetaData::paddr_is_valid`].
+    InvalidPhysAddr(PhysAddr),
+    /// The size in bytes is not a page size supported by the page table.
+    UnsupportedPageSize(usize),
 }
 
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::NoMemory => write!(f, "cannot allocate memory"),
+            Self::NotAligned { vaddr } => {
+                write!(f, "{vaddr:#x} is not aligned to the page size")
+            }
+            Self::NotMapped { vaddr, level } => {
+                write!(f, "{vaddr:#x} is not mapped at level {level}")
+            }
+            Self::AlreadyMapped {
+                vaddr,
+                level,
+                entry,
+            } => write!(
+                f,
+                "{vaddr:#x} is already mapped at level {level} by entry {entry:#x}"
+            ),
+            Self::MappedToHugePage { vaddr, level } => {
+                write!(f, "{vaddr:#x} is mapped by a huge page at level {level}")
+            }
+            Self::Swapped(payload) => write!(f, "the page is swapped out ({payload:#x})"),
+            Self::InvalidSwapPayload(payload) => write!(f, "swap payload too large: {payload:#x}"),
+            Self::InvalidVirtAddr(vaddr) => write!(f, "invalid virtual address {vaddr:#x}"),
+            Self::InvalidPhysAddr(paddr) => {
+                write!(f, "invalid physical address {:#x}", paddr.as_usize())
+            }
+            Self::UnsupportedPageSize(size) => write!(f, "unsupported page size {size:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for PagingError {}
+
 /// The specialized `Result` type for page table operations.
 pub type PagingResult<T = ()> = Result<T, PagingError>;
 
@@ -48,8 +127,44 @@ pub trait PagingMetaData: Sync + Send {
     /// The maximum number of bits of virtual address.
     const VA_MAX_BITS: usize;
 
+    /// The number of bits of the offset within a base page, i.e., the page
+    /// mapped by a last level entry is `1 << PAGE_SHIFT` bytes.
+    ///
+    /// Every page table occupies one base page.
+    const PAGE_SHIFT: usize = 12;
+    /// The number of virtual address bits indexed by each non-root level.
+    const LEVEL_INDEX_BITS: usize = 9;
+    /// The number of virtual address bits indexed by the root level.
+    ///
+    /// It can be less than [`PagingMetaData::LEVEL_INDEX_BITS`] when the
+    /// virtual address space is not a multiple of the levels.
+    const ROOT_INDEX_BITS: usize = Self::LEVEL_INDEX_BITS;
+    /// Whether a valid entry must be made invalid, and the TLB flushed, before
+    /// it is changed to map the same range with a different page size.
+    ///
+    /// It is the "break-before-make" rule of ARM architectures, and applies
+    /// when huge pages are split or collapsed.
+    const BREAK_BEFORE_MAKE: bool = false;
+    /// The maximum number of pages that
+    /// [`flush_tlb_range`](Self::flush_tlb_range) invalidates by address, one
+    /// by one or with range instructions. Larger ranges are flushed by
+    /// flushing the whole TLB.
+    const FLUSH_RANGE_MAX_PAGES: usize = 32;
+    /// Whether the lower and upper halves of the virtual address space are
+    /// translated from separate root tables, each covering `VA_MAX_BITS` bits.
+    ///
+    /// The metadata types that set it implement [`SplitRootsMetaData`] as
+    /// well. See [`SplitAddressSpace`].
+    const SPLIT_ROOTS: bool = false;
+
     /// The maximum physical address.
-    const PA_MAX_ADDR: usize = (1 << Self::PA_MAX_BITS) - 1;
+    ///
+    /// It is clamped to `usize::MAX` when `PA_MAX_BITS` is not less than the
+    /// width of `usize`, e.g., for Sv32 on 32-bit hosts.
+    const PA_MAX_ADDR: usize = match 1usize.checked_shl(Self::PA_MAX_BITS as u32) {
+        Some(limit) => limit - 1,
+        None => usize::MAX,
+    };
 
     /// The virtual address to be translated in this page table.
     ///
@@ -76,21 +191,257 @@ pub trait PagingMetaData: Sync + Send {
     ///
     /// If `vaddr` is [`None`], flushes the entire TLB. Otherwise, flushes the
     /// TLB entry at the given virtual address.
+    ///
+    /// The metadata types in this crate can be used on any host, but only
+    /// flush the TLB when the target architecture matches. Otherwise the page
+    /// table can never be active on the current CPU, and this is a no-op.
     fn flush_tlb(vaddr: Option<Self::VirtAddr>);
+
+    /// Flushes the TLB entries tagged with the address space identifier
+    /// `asid`, i.e., the ASID, PCID or VMID, depending on the architecture.
+    ///
+    /// If `vaddr` is [`None`], flushes all entries of `asid`. Otherwise,
+    /// flushes the entry of `asid` at the given virtual address. Global
+    /// mappings may be left in the TLB.
+    ///
+    /// The default implementation flushes for all address spaces by
+    /// [`flush_tlb`](Self::flush_tlb).
+    #[inline]
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        let _ = asid;
+        Self::flush_tlb(vaddr);
+    }
+
+    /// Flushes the TLB entries of the pages of `stride` bytes in the virtual
+    /// address range `[start, end)`, for the address space `asid` if it is
+    /// given, or all address spaces otherwise.
+    ///
+    /// It is how [`PageTable64Mut::commit`] flushes the changed pages, so the
+    /// architectures that can invalidate a range at once can override it. The
+    /// default implementation flushes the pages one by one, unless there are
+    /// more than [`FLUSH_RANGE_MAX_PAGES`](Self::FLUSH_RANGE_MAX_PAGES).
+    fn flush_tlb_range(
+        start: Self::VirtAddr,
+        end: Self::VirtAddr,
+        stride: usize,
+        asid: Option<u16>,
+    ) {
+        let flush_tlb = |vaddr| match asid {
+            Some(asid) => Self::flush_tlb_asid(asid, vaddr),
+            None => Self::flush_tlb(vaddr),
+        };
+        let start: usize = start.into();
+        let pages = end.into().wrapping_sub(start) / stride;
+        if pages > Self::FLUSH_RANGE_MAX_PAGES {
+            flush_tlb(None);
+        } else {
+            for i in 0..pages {
+                flush_tlb(Some(start.wrapping_add(i * stride).into()));
+            }
+        }
+    }
+
+    /// Installs the page table at `root` as the current page table of this
+    /// CPU, tagged with the address space identifier `asid` if it is given.
+    ///
+    /// It writes the root register of the architecture (e.g., CR3, `satp` or
+    /// `TTBR0_EL1`) with the mode bits of this page table format. The TLB
+    /// entries of the previous page table may be kept, so it is usually called
+    /// through [`PageTable64::activate`], which flushes them. The default
+    /// implementation does nothing, for page tables installed in other ways,
+    /// such as EPTs referenced by a VMCS.
+    ///
+    /// # Safety
+    ///
+    /// `root` must be a valid root page table of this format that maps the
+    /// code and data in use by the current CPU.
+    #[inline]
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        let _ = (root, asid);
+    }
+
+    /// Returns the root page table installed by [`activate`](Self::activate)
+    /// on the current CPU, as read from the root register.
+    ///
+    /// It is [`None`] if the root cannot be read, including when the target
+    /// architecture does not match. The default implementation returns
+    /// [`None`].
+    #[inline]
+    fn read_current_root() -> Option<PhysAddr> {
+        None
+    }
 }
 
 /// The low-level **OS-dependent** helpers that must be provided for
 /// [`PageTable64`].
+///
+/// Each page table holds its own handler value, so page tables of the same
+/// type can use different frame allocators or physical memory mappings. For
+/// handlers without state, see [`StaticPagingHandler`].
 pub trait PagingHandler: Sized {
-    /// Request to allocate a 4K-sized physical frame.
-    fn alloc_frame() -> Option<PhysAddr>;
+    /// Request to allocate a physical frame of one base page.
+    ///
+    /// The frame is 4K-sized, unless the page table uses a larger translation
+    /// granule (see [`PagingMetaData::PAGE_SHIFT`]). In that case, the frame
+    /// must be of the granule size and aligned to it.
+    fn alloc_frame(&self) -> Option<PhysAddr>;
     /// Request to free a allocated physical frame.
-    fn dealloc_frame(paddr: PhysAddr);
+    fn dealloc_frame(&self, paddr: PhysAddr);
+    /// Request to allocate `count` contiguous physical frames of one base page
+    /// each, starting at an address aligned to `align` bytes.
+    ///
+    /// It is used to allocate root page tables, which may span several frames
+    /// (e.g., RISC-V Sv39x4 G-stage tables, or AArch64 stage 2 tables with
+    /// concatenated initial lookup levels). The default implementation only
+    /// supports a single frame, as returned by [`alloc_frame`](Self::alloc_frame).
+    fn alloc_frames(&self, count: usize, align: usize) -> Option<PhysAddr> {
+        if count != 1 {
+            return None;
+        }
+        let paddr = self.alloc_frame()?;
+        if paddr.is_aligned(align) {
+            Some(paddr)
+        } else {
+            self.dealloc_frame(paddr);
+            None
+        }
+    }
+    /// Request to free `count` contiguous physical frames allocated by
+    /// [`alloc_frames`](Self::alloc_frames).
+    ///
+    /// It must be implemented together with `alloc_frames`.
+    fn dealloc_frames(&self, paddr: PhysAddr, count: usize) {
+        debug_assert_eq!(count, 1);
+        self.dealloc_frame(paddr);
+    }
     /// Returns a virtual address that maps to the given physical address.
     ///
     /// Used to access the physical memory directly in page table
     /// implementation.
+    fn phys_to_virt(&self, paddr: PhysAddr) -> VirtAddr;
+    /// Requests the other CPUs that may use the page table to flush their TLB
+    /// as well, e.g., by sending IPIs to the CPUs in its active mask.
+    ///
+    /// It is called by [`PageTable64Mut::commit`] after the TLB of the current
+    /// CPU is flushed, with the ASID of the page table (see
+    /// [`PageTable64::set_asid`]) and the entries flushed. The tables freed by
+    /// the commit are deallocated after it returns, so it must wait for the
+    /// other CPUs to finish. The default implementation does nothing.
+    fn shootdown(&self, asid: Option<u16>, flush: TlbFlush<'_>) {
+        let _ = (asid, flush);
+    }
+}
+
+/// A virtual address range `[start, end)` of pages of `stride` bytes, whose
+/// TLB entries are flushed. See [`PagingMetaData::flush_tlb_range`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbRange {
+    /// The start address of the first page.
+    pub start: usize,
+    /// The end address of the last page, exclusive.
+    pub end: usize,
+    /// The page size.
+    pub stride: usize,
+}
+
+/// The TLB entries flushed by a commit, as passed to
+/// [`PagingHandler::shootdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbFlush<'a> {
+    /// The entries of the pages in the ranges.
+    Ranges(&'a [TlbRange]),
+    /// The whole TLB, or all entries of the ASID.
+    All,
+}
+
+/// The **OS-dependent** helpers for page tables, as global functions shared by
+/// all page tables.
+///
+/// Use it through the zero-sized [`StaticHandler`] adapter.
+pub trait StaticPagingHandler {
+    /// See [`PagingHandler::alloc_frame`].
+    fn alloc_frame() -> Option<PhysAddr>;
+    /// See [`PagingHandler::dealloc_frame`].
+    fn dealloc_frame(paddr: PhysAddr);
+    /// See [`PagingHandler::alloc_frames`].
+    fn alloc_frames(count: usize, align: usize) -> Option<PhysAddr> {
+        if count != 1 {
+            return None;
+        }
+        let paddr = Self::alloc_frame()?;
+        if paddr.is_aligned(align) {
+            Some(paddr)
+        } else {
+            Self::dealloc_frame(paddr);
+            None
+        }
+    }
+    /// See [`PagingHandler::dealloc_frames`].
+    fn dealloc_frames(paddr: PhysAddr, count: usize) {
+        debug_assert_eq!(count, 1);
+        Self::dealloc_frame(paddr);
+    }
+    /// See [`PagingHandler::phys_to_virt`].
     fn phys_to_virt(paddr: PhysAddr) -> VirtAddr;
+    /// See [`PagingHandler::shootdown`].
+    fn shootdown(asid: Option<u16>, flush: TlbFlush<'_>) {
+        let _ = (asid, flush);
+    }
+}
+
+/// A zero-sized [`PagingHandler`] that forwards to the global functions of a
+/// [`StaticPagingHandler`].
+///
+/// For example, `X64PageTable<StaticHandler<H>>` works like
+/// `X64PageTable<H>` did when handlers had no state.
+pub struct StaticHandler<H>(PhantomData<fn() -> H>);
+
+impl<H> StaticHandler<H> {
+    /// Creates the adapter.
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<H> Default for StaticHandler<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> Clone for StaticHandler<H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H> Copy for StaticHandler<H> {}
+
+impl<H: StaticPagingHandler> PagingHandler for StaticHandler<H> {
+    #[inline]
+    fn alloc_frame(&self) -> Option<PhysAddr> {
+        H::alloc_frame()
+    }
+    #[inline]
+    fn dealloc_frame(&self, paddr: PhysAddr) {
+        H::dealloc_frame(paddr)
+    }
+    #[inline]
+    fn alloc_frames(&self, count: usize, align: usize) -> Option<PhysAddr> {
+        H::alloc_frames(count, align)
+    }
+    #[inline]
+    fn dealloc_frames(&self, paddr: PhysAddr, count: usize) {
+        H::dealloc_frames(paddr, count)
+    }
+    #[inline]
+    fn phys_to_virt(&self, paddr: PhysAddr) -> VirtAddr {
+        H::phys_to_virt(paddr)
+    }
+    #[inline]
+    fn shootdown(&self, asid: Option<u16>, flush: TlbFlush<'_>) {
+        H::shootdown(asid, flush)
+    }
 }
 
 /// The page sizes supported by the hardware page table.
@@ -99,8 +450,20 @@ pub trait PagingHandler: Sized {
 pub enum PageSize {
     /// Size of 4 kilobytes (2<sup>12</sup> bytes).
     Size4K = 0x1000,
+    /// Size of 16 kilobytes (2<sup>14</sup> bytes).
+    Size16K = 0x4000,
+    /// Size of 64 kilobytes (2<sup>16</sup> bytes).
+    Size64K = 0x1_0000,
+    /// Size of 1 megabytes (2<sup>20</sup> bytes).
+    Size1M = 0x10_0000,
     /// Size of 2 megabytes (2<sup>21</sup> bytes).
     Size2M = 0x20_0000,
+    /// Size of 4 megabytes (2<sup>22</sup> bytes).
+    Size4M = 0x40_0000,
+    /// Size of 32 megabytes (2<sup>25</sup> bytes).
+    Size32M = 0x200_0000,
+    /// Size of 512 megabytes (2<sup>29</sup> bytes).
+    Size512M = 0x2000_0000,
     /// Size of 1 gigabytes (2<sup>30</sup> bytes).
     Size1G = 0x4000_0000,
 }
@@ -108,7 +471,7 @@ pub enum PageSize {
 impl PageSize {
     /// Whether this page size is considered huge (larger than 4K).
     pub const fn is_huge(self) -> bool {
-        matches!(self, Self::Size1G | Self::Size2M)
+        !matches!(self, Self::Size4K)
     }
 
     /// Checks whether a given address or size is aligned to the page size.
@@ -128,3 +491,27 @@ impl From<PageSize> for usize {
         size as usize
     }
 }
+
+impl TryFrom<usize> for PageSize {
+    type Error = PagingError;
+
+    /// Converts a size in bytes to the page size.
+    ///
+    /// Returns
+    /// [`Err(PagingError::UnsupportedPageSize)`](PagingError::UnsupportedPageSize)
+    /// if there is no page of that size.
+    fn try_from(size: usize) -> PagingResult<Self> {
+        Ok(match size {
+            0x1000 => Self::Size4K,
+            0x4000 => Self::Size16K,
+            0x1_0000 => Self::Size64K,
+            0x10_0000 => Self::Size1M,
+            0x20_0000 => Self::Size2M,
+            0x40_0000 => Self::Size4M,
+            0x200_0000 => Self::Size32M,
+            0x2000_0000 => Self::Size512M,
+            0x4000_0000 => Self::Size1G,
+            _ => return Err(PagingError::UnsupportedPageSize(size)),
+        })
+    }
+}
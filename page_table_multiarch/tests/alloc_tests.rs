@@ -1,58 +1,122 @@
 use std::{
     alloc::{self, Layout},
-    cell::RefCell,
-    collections::HashSet,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
     marker::PhantomData,
 };
 
-use memory_addr::{PhysAddr, VirtAddr};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr, VirtAddr};
 use page_table_entry::{GenericPTE, MappingFlags};
-use page_table_multiarch::{PageSize, PageTable64, PagingHandler, PagingMetaData, PagingResult};
+use page_table_multiarch::{
+    PageSize, PageTable64, PagingError, PagingHandler, PagingMetaData, PagingResult,
+    SplitRootsMetaData, SplitSpaceMetaData, StaticHandler, StaticPagingHandler, TlbFlush, TlbRange,
+};
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 
-const PAGE_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) };
+/// Tracks the frames allocated through it.
+///
+/// Host addresses may exceed `PA_MAX_ADDR` of the tested architecture, so
+/// frames are given small fake physical addresses instead.
+struct TrackPagingHandler<M: PagingMetaData> {
+    /// Maps the fake physical address of each allocation to its host address
+    /// and layout.
+    allocated: RefCell<HashMap<usize, (usize, Layout)>>,
+    next_paddr: Cell<usize>,
+    _phantom: PhantomData<M>,
+}
 
-thread_local! {
-    static ALLOCATED: RefCell<HashSet<usize>> = RefCell::default();
+impl<M: PagingMetaData> Default for TrackPagingHandler<M> {
+    fn default() -> Self {
+        Self {
+            allocated: RefCell::default(),
+            next_paddr: Cell::new(PAGE_SIZE_4K),
+            _phantom: PhantomData,
+        }
+    }
 }
 
-struct TrackPagingHandler<M: PagingMetaData>(PhantomData<M>);
+impl<M: PagingMetaData> PagingHandler for &TrackPagingHandler<M> {
+    fn alloc_frame(&self) -> Option<PhysAddr> {
+        self.alloc_frames(1, 1 << M::PAGE_SHIFT)
+    }
 
-impl<M: PagingMetaData> PagingHandler for TrackPagingHandler<M> {
-    fn alloc_frame() -> Option<PhysAddr> {
-        let ptr = unsafe { alloc::alloc(PAGE_LAYOUT) } as usize;
+    fn dealloc_frame(&self, paddr: PhysAddr) {
+        self.dealloc_frames(paddr, 1)
+    }
+
+    fn alloc_frames(&self, count: usize, align: usize) -> Option<PhysAddr> {
+        let layout = Layout::from_size_align(count << M::PAGE_SHIFT, align).unwrap();
+        let ptr = unsafe { alloc::alloc(layout) } as usize;
+        let paddr = self.next_paddr.get().align_up(align);
+        self.next_paddr.set(paddr + layout.size());
         assert!(
-            ptr <= M::PA_MAX_ADDR,
+            paddr <= M::PA_MAX_ADDR,
             "allocated frame address exceeds PA_MAX_ADDR"
         );
-        ALLOCATED.with_borrow_mut(|it| it.insert(ptr));
-        Some(PhysAddr::from_usize(ptr))
+        self.allocated.borrow_mut().insert(paddr, (ptr, layout));
+        Some(PhysAddr::from_usize(paddr))
     }
 
-    fn dealloc_frame(paddr: PhysAddr) {
-        let ptr = paddr.as_usize();
-        ALLOCATED.with_borrow_mut(|it| {
-            assert!(it.remove(&ptr), "dealloc a frame that was not allocated");
-        });
+    fn dealloc_frames(&self, paddr: PhysAddr, count: usize) {
+        let (ptr, layout) = self
+            .allocated
+            .borrow_mut()
+            .remove(&paddr.as_usize())
+            .expect("dealloc a frame that was not allocated");
+        assert_eq!(layout.size(), count << M::PAGE_SHIFT);
         unsafe {
-            alloc::dealloc(ptr as _, PAGE_LAYOUT);
+            alloc::dealloc(ptr as _, layout);
         }
     }
 
-    fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
-        assert!(paddr.as_usize() > 0);
-        VirtAddr::from_usize(paddr.as_usize())
+    fn phys_to_virt(&self, paddr: PhysAddr) -> VirtAddr {
+        let ptr = self
+            .allocated
+            .borrow()
+            .get(&paddr.as_usize())
+            .map(|&(ptr, _)| ptr);
+        VirtAddr::from_usize(ptr.expect("access a frame that was not allocated"))
     }
 }
 
-fn run_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>() -> PagingResult<()> {
-    ALLOCATED.with_borrow_mut(|it| {
-        it.clear();
-    });
+/// Wraps the metadata of an architecture, but never flushes the TLB of the
+/// host CPU.
+struct NoFlushMetaData<M: PagingMetaData>(PhantomData<M>);
+
+impl<M: PagingMetaData> PagingMetaData for NoFlushMetaData<M> {
+    const LEVELS: usize = M::LEVELS;
+    const PA_MAX_BITS: usize = M::PA_MAX_BITS;
+    const VA_MAX_BITS: usize = M::VA_MAX_BITS;
+    const PAGE_SHIFT: usize = M::PAGE_SHIFT;
+    const LEVEL_INDEX_BITS: usize = M::LEVEL_INDEX_BITS;
+    const ROOT_INDEX_BITS: usize = M::ROOT_INDEX_BITS;
+    const BREAK_BEFORE_MAKE: bool = M::BREAK_BEFORE_MAKE;
+    const SPLIT_ROOTS: bool = M::SPLIT_ROOTS;
+    type VirtAddr = M::VirtAddr;
 
-    let vaddr_mask = ((1u64 << M::VA_MAX_BITS) - 1) & !0xfff;
+    fn paddr_is_valid(paddr: usize) -> bool {
+        M::paddr_is_valid(paddr)
+    }
+
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        M::vaddr_is_valid(vaddr)
+    }
+
+    fn flush_tlb(_vaddr: Option<Self::VirtAddr>) {}
+}
+
+impl<M: SplitRootsMetaData> SplitRootsMetaData for NoFlushMetaData<M> {}
+
+fn run_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>() -> PagingResult<()> {
+    // the lower half, which is valid whether or not addresses are sign-extended
+    let vaddr_mask = ((1u64 << (M::VA_MAX_BITS - 1)) - 1) & !((1 << M::PAGE_SHIFT) - 1);
+    let paddr_mask = ((1u64 << M::PA_MAX_BITS) - 1) & !((1 << M::PAGE_SHIFT) - 1);
+    let page_size = PageSize::try_from(1 << M::PAGE_SHIFT)?;
 
-    let mut table = PageTable64::<M, PTE, TrackPagingHandler<M>>::try_new().unwrap();
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let root_size = ((1 << M::ROOT_INDEX_BITS) * size_of::<PTE>()).max(1 << M::PAGE_SHIFT);
+    assert!(table.root_paddr().is_aligned(root_size));
     let mut pages = HashSet::new();
     let mut rng = SmallRng::seed_from_u64(1234);
     for _ in 0..2048 {
@@ -64,51 +128,97 @@ fn run_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>() -> Pa
                     break addr;
                 }
             };
-            table
-                .map(
-                    VirtAddr::from_usize(addr as usize),
-                    PhysAddr::from_usize((rng.random::<u64>() & vaddr_mask) as usize),
-                    PageSize::Size4K,
-                    MappingFlags::READ | MappingFlags::WRITE,
-                )?
-                .ignore();
+            let vaddr = VirtAddr::from_usize(addr as usize);
+            let paddr = PhysAddr::from_usize((rng.random::<u64>() & paddr_mask) as usize);
+            let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+            table.to_mut().map(vaddr, paddr, page_size, flags)?;
+            assert_eq!(table.query(vaddr)?, (paddr, flags, page_size));
         } else {
             // remove a mapping
             let addr = *pages.iter().next().unwrap();
-            table.unmap(VirtAddr::from_usize(addr as usize))?.2.ignore();
+            table.to_mut().unmap(VirtAddr::from_usize(addr as usize))?;
             pages.remove(&addr);
         }
     }
 
     drop(table);
-    assert_eq!(
-        ALLOCATED.with_borrow(|it| it.len()),
-        0,
+    assert!(
+        handler.allocated.borrow().is_empty(),
         "Some frames were not deallocated"
     );
 
     Ok(())
 }
 
+fn run_huge_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>(
+    huge_size: PageSize,
+) -> PagingResult<()> {
+    let base_size = PageSize::try_from(1 << M::PAGE_SHIFT)?;
+    let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+    let vaddr = VirtAddr::from_usize(huge_size as usize * 3);
+    let offset = huge_size as usize * 5;
+
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    table.to_mut().map_region(
+        vaddr,
+        |vaddr| PhysAddr::from_usize(vaddr.as_usize() + offset),
+        huge_size as usize + base_size as usize,
+        flags,
+        true,
+    )?;
+    let paddr = PhysAddr::from_usize(vaddr.as_usize() + offset);
+    assert_eq!(table.query(vaddr)?, (paddr, flags, huge_size));
+    assert_eq!(
+        table.query(vaddr + base_size as usize)?,
+        (paddr + base_size as usize, flags, huge_size)
+    );
+    assert_eq!(
+        table.query(vaddr + huge_size as usize)?,
+        (paddr + huge_size as usize, flags, base_size)
+    );
+    let unmapped = vaddr + huge_size as usize + base_size as usize;
+    assert_eq!(
+        table.query(unmapped),
+        Err(PagingError::NotMapped {
+            vaddr: unmapped.as_usize(),
+            level: M::LEVELS - 1
+        })
+    );
+    Ok(())
+}
+
 #[test]
 fn test_dealloc_x86() -> PagingResult<()> {
     run_test_for::<
         page_table_multiarch::x86_64::X64PagingMetaData,
         page_table_entry::x86_64::X64PTE,
     >()?;
+    run_test_for::<
+        page_table_multiarch::x86_64::X64LA57PagingMetaData,
+        page_table_entry::x86_64::X64PTE,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::x86_64::X86PagingMetaData32,
+        page_table_entry::x86_64::X86PTE32,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::x86_64::ExtendedPageTable,
+        page_table_entry::x86_64::EPTEntry,
+    >()?;
     Ok(())
 }
 
 #[test]
 fn test_dealloc_riscv() -> PagingResult<()> {
-    run_test_for::<
-        page_table_multiarch::riscv::Sv39MetaData<VirtAddr>,
-        page_table_entry::riscv::Rv64PTE,
-    >()?;
-    run_test_for::<
-        page_table_multiarch::riscv::Sv48MetaData<VirtAddr>,
-        page_table_entry::riscv::Rv64PTE,
-    >()?;
+    run_test_for::<page_table_multiarch::riscv::Sv32MetaData, page_table_entry::riscv::Sv32PTE>()?;
+    run_test_for::<page_table_multiarch::riscv::Sv39MetaData, page_table_entry::riscv::Rv64PTE>()?;
+    run_test_for::<page_table_multiarch::riscv::Sv48MetaData, page_table_entry::riscv::Rv64PTE>()?;
+    run_test_for::<page_table_multiarch::riscv::Sv57MetaData, page_table_entry::riscv::Rv64PTE>()?;
+    run_test_for::<page_table_multiarch::riscv::Sv39x4MetaData, page_table_entry::riscv::Rv64PTE>(
+    )?;
+    run_test_for::<page_table_multiarch::riscv::Sv48x4MetaData, page_table_entry::riscv::Rv64PTE>(
+    )?;
     Ok(())
 }
 
@@ -118,6 +228,79 @@ fn test_dealloc_aarch64() -> PagingResult<()> {
         page_table_multiarch::aarch64::A64PagingMetaData,
         page_table_entry::aarch64::A64PTE,
     >()?;
+    run_test_for::<
+        page_table_multiarch::aarch64::A64Granule16KMetaData,
+        page_table_entry::aarch64::A64PTE,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::aarch64::A64Granule64KMetaData,
+        page_table_entry::aarch64::A64PTE,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::aarch64::A64Stage2MetaData,
+        page_table_entry::aarch64::A64S2PTE,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::aarch64::A64Stage2MetaData<VirtAddr, 40>,
+        page_table_entry::aarch64::A64S2PTE,
+    >()?;
+    run_test_for::<
+        page_table_multiarch::aarch64::A64Stage2MetaData<VirtAddr, 34>,
+        page_table_entry::aarch64::A64S2PTE,
+    >()?;
+    Ok(())
+}
+
+#[test]
+fn test_dealloc_arm() -> PagingResult<()> {
+    use page_table_entry::arm::A32PTE;
+    use page_table_multiarch::arm::A32PagingMetaData;
+
+    run_test_for::<A32PagingMetaData, A32PTE>()?;
+    run_test_for::<A32PagingMetaData<0>, A32PTE>()?;
+    run_test_for::<A32PagingMetaData<1>, A32PTE>()?;
+    Ok(())
+}
+
+#[test]
+fn test_arm_ttbr_regions() -> PagingResult<()> {
+    use page_table_entry::arm::A32PTE;
+    use page_table_multiarch::arm::{A32PagingMetaData, A32Ttbr1MetaData};
+
+    fn check<M: PagingMetaData<VirtAddr = VirtAddr>>(vaddr: usize) -> PagingResult {
+        let handler = TrackPagingHandler::<M>::default();
+        let mut table = PageTable64::<NoFlushMetaData<M>, A32PTE, _>::try_new_in(&handler)?;
+        let paddr = PhysAddr::from_usize(0x1000);
+        let flags = MappingFlags::READ;
+        table
+            .to_mut()
+            .map(VirtAddr::from_usize(vaddr), paddr, PageSize::Size4K, flags)
+    }
+
+    // `TTBR0` translates below `4G >> N`, and `TTBR1` the rest
+    assert_eq!(check::<A32PagingMetaData>(0x3fff_f000), Ok(()));
+    assert_eq!(
+        check::<A32PagingMetaData>(0x4000_0000),
+        Err(PagingError::InvalidVirtAddr(0x4000_0000))
+    );
+    assert_eq!(
+        check::<A32Ttbr1MetaData>(0x3fff_f000),
+        Err(PagingError::InvalidVirtAddr(0x3fff_f000))
+    );
+    assert_eq!(check::<A32Ttbr1MetaData>(0x4000_0000), Ok(()));
+    assert_eq!(check::<A32Ttbr1MetaData>(0xffff_f000), Ok(()));
+    assert_eq!(check::<A32PagingMetaData<1>>(0x7fff_f000), Ok(()));
+    assert_eq!(check::<A32Ttbr1MetaData<1>>(0x8000_0000), Ok(()));
+    assert_eq!(
+        check::<A32Ttbr1MetaData<1>>(0x7fff_f000),
+        Err(PagingError::InvalidVirtAddr(0x7fff_f000))
+    );
+    // with `N` = 0, `TTBR0` translates everything
+    assert_eq!(check::<A32PagingMetaData<0>>(0xffff_f000), Ok(()));
+    assert_eq!(
+        check::<A32Ttbr1MetaData<0>>(0xffff_f000),
+        Err(PagingError::InvalidVirtAddr(0xffff_f000))
+    );
     Ok(())
 }
 
@@ -129,3 +312,1461 @@ fn test_dealloc_loongarch64() -> PagingResult<()> {
     >()?;
     Ok(())
 }
+
+#[test]
+fn test_huge_pages() -> PagingResult<()> {
+    use page_table_entry::{
+        aarch64::{A64PTE, A64S2PTE},
+        arm::A32PTE,
+        riscv::{Rv64PTE, Sv32PTE},
+        x86_64::{EPTEntry, X64PTE, X86PTE32},
+    };
+    use page_table_multiarch::{aarch64::*, arm::*, riscv::*, x86_64::*};
+
+    run_huge_test_for::<X64PagingMetaData, X64PTE>(PageSize::Size2M)?;
+    run_huge_test_for::<X64PagingMetaData, X64PTE>(PageSize::Size1G)?;
+    run_huge_test_for::<X64LA57PagingMetaData, X64PTE>(PageSize::Size1G)?;
+    run_huge_test_for::<ExtendedPageTable, EPTEntry>(PageSize::Size2M)?;
+    run_huge_test_for::<A64PagingMetaData, A64PTE>(PageSize::Size2M)?;
+    run_huge_test_for::<A64Stage2MetaData, A64S2PTE>(PageSize::Size1G)?;
+    run_huge_test_for::<A64Stage2MetaData<VirtAddr, 40>, A64S2PTE>(PageSize::Size1G)?;
+    run_huge_test_for::<A64Granule16KMetaData, A64PTE>(PageSize::Size32M)?;
+    run_huge_test_for::<A64Granule64KMetaData, A64PTE>(PageSize::Size512M)?;
+    run_huge_test_for::<X86PagingMetaData32, X86PTE32>(PageSize::Size4M)?;
+    run_huge_test_for::<Sv32MetaData, Sv32PTE>(PageSize::Size4M)?;
+    run_huge_test_for::<Sv39x4MetaData, Rv64PTE>(PageSize::Size1G)?;
+    run_huge_test_for::<A32PagingMetaData, A32PTE>(PageSize::Size1M)?;
+    Ok(())
+}
+
+thread_local! {
+    static STATIC_HANDLER: TrackPagingHandler<page_table_multiarch::x86_64::X64PagingMetaData> =
+        TrackPagingHandler::default();
+}
+
+struct StaticTrackPagingHandler;
+
+impl StaticPagingHandler for StaticTrackPagingHandler {
+    fn alloc_frame() -> Option<PhysAddr> {
+        STATIC_HANDLER.with(|h| h.alloc_frame())
+    }
+
+    fn dealloc_frame(paddr: PhysAddr) {
+        STATIC_HANDLER.with(|h| h.dealloc_frame(paddr))
+    }
+
+    fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+        STATIC_HANDLER.with(|h| h.phys_to_virt(paddr))
+    }
+}
+
+#[test]
+fn test_handler_instances() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let vaddr = VirtAddr::from_usize(0x1000);
+    let paddr = PhysAddr::from_usize(0x2000);
+
+    // each table allocates from its own handler
+    let handler1 = TrackPagingHandler::<X64PagingMetaData>::default();
+    let handler2 = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table1 = PageTable64::<M, X64PTE, _>::try_new_in(&handler1)?;
+    let table2 = PageTable64::<M, X64PTE, _>::try_new_in(&handler2)?;
+    table1.to_mut().map(vaddr, paddr, PageSize::Size4K, flags)?;
+    assert_eq!(handler1.allocated.borrow().len(), 4);
+    assert_eq!(handler2.allocated.borrow().len(), 1);
+    assert_eq!(
+        table2.query(vaddr),
+        Err(PagingError::NotMapped {
+            vaddr: 0x1000,
+            level: 0
+        })
+    );
+    drop(table1);
+    drop(table2);
+    assert!(handler1.allocated.borrow().is_empty());
+    assert!(handler2.allocated.borrow().is_empty());
+
+    // static handlers work through the zero-sized adapter
+    let mut table = PageTable64::<M, X64PTE, StaticHandler<StaticTrackPagingHandler>>::try_new()?;
+    table.to_mut().map(vaddr, paddr, PageSize::Size4K, flags)?;
+    assert_eq!(table.query(vaddr)?, (paddr, flags, PageSize::Size4K));
+    drop(table);
+    assert!(STATIC_HANDLER.with(|h| h.allocated.borrow().is_empty()));
+    Ok(())
+}
+
+#[test]
+fn test_cursor() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let mut pt = table.to_mut();
+
+    // map a 4K page, a 2M page and another 4K page in the same 1G region
+    let mut cursor = pt.cursor(VirtAddr::from_usize(0x1000));
+    cursor.map(PhysAddr::from_usize(0x1000), PageSize::Size4K, flags)?;
+    cursor.seek(VirtAddr::from_usize(0x40_0000));
+    cursor.map(PhysAddr::from_usize(0x20_0000), PageSize::Size2M, flags)?;
+    assert_eq!(
+        cursor.map(PhysAddr::from_usize(0x20_0000), PageSize::Size2M, flags),
+        Err(PagingError::AlreadyMapped {
+            vaddr: 0x40_0000,
+            level: 2,
+            entry: X64PTE::new_page(PhysAddr::from_usize(0x20_0000), flags, true).bits()
+        })
+    );
+    cursor.advance(0x20_0000);
+    cursor.map(PhysAddr::from_usize(0x5000), PageSize::Size4K, flags)?;
+
+    // query and step through the region
+    cursor.seek(VirtAddr::from_usize(0x1000));
+    assert_eq!(
+        cursor.query()?,
+        (PhysAddr::from_usize(0x1000), flags, PageSize::Size4K)
+    );
+    assert_eq!(cursor.step(), 0x1000);
+    assert_eq!(
+        cursor.query(),
+        Err(PagingError::NotMapped {
+            vaddr: 0x2000,
+            level: 3
+        })
+    );
+    assert_eq!(cursor.step(), 0x1000);
+    cursor.seek(VirtAddr::from_usize(0x20_0000));
+    assert_eq!(cursor.step(), 0x20_0000);
+    assert_eq!(cursor.vaddr(), VirtAddr::from_usize(0x40_0000));
+    cursor.advance(0x1234);
+    assert_eq!(
+        cursor.query()?,
+        (PhysAddr::from_usize(0x20_1234), flags, PageSize::Size2M)
+    );
+    cursor.seek(VirtAddr::from_usize(0x40_0000));
+    assert_eq!(cursor.step(), 0x20_0000);
+    assert_eq!(
+        cursor.query()?,
+        (PhysAddr::from_usize(0x5000), flags, PageSize::Size4K)
+    );
+    // empty subtrees are skipped as a whole
+    cursor.seek(VirtAddr::from_usize(0x4000_0000));
+    assert_eq!(cursor.step(), 0x4000_0000);
+    cursor.seek(VirtAddr::from_usize(0x80_0000_0000));
+    assert_eq!(cursor.step(), 0x80_0000_0000);
+
+    // protect and unmap at the current position
+    cursor.seek(VirtAddr::from_usize(0x40_0000));
+    assert_eq!(cursor.protect(MappingFlags::READ)?, PageSize::Size2M);
+    assert_eq!(
+        cursor.unmap()?,
+        (
+            PhysAddr::from_usize(0x20_0000),
+            MappingFlags::READ,
+            PageSize::Size2M
+        )
+    );
+    let not_mapped = PagingError::NotMapped {
+        vaddr: 0x40_0000,
+        level: 2,
+    };
+    assert_eq!(cursor.unmap(), Err(not_mapped));
+    drop(pt);
+    assert_eq!(
+        table.query(VirtAddr::from_usize(0x40_0000)),
+        Err(not_mapped)
+    );
+    assert_eq!(
+        table.query(VirtAddr::from_usize(0x60_0000))?,
+        (PhysAddr::from_usize(0x5000), flags, PageSize::Size4K)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_mappings() -> PagingResult<()> {
+    use page_table_entry::{aarch64::A64PTE, arm::A32PTE, riscv::Rv64PTE, x86_64::X64PTE};
+    use page_table_multiarch::{
+        aarch64::A64PagingMetaData, arm::A32Ttbr1MetaData, riscv::Sv39MetaData,
+        x86_64::X64PagingMetaData,
+    };
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    let mut pt = table.to_mut();
+    // two contiguous 4K pages, then one with other flags
+    pt.map_region(
+        va(0x1000),
+        |v| pa(v.as_usize() + 0x10_0000),
+        0x2000,
+        rw,
+        false,
+    )?;
+    pt.map(
+        va(0x3000),
+        pa(0x10_3000),
+        PageSize::Size4K,
+        MappingFlags::READ,
+    )?;
+    // a 2M page, and a 4K page in a far away subtree
+    pt.map(va(0x20_0000), pa(0x40_0000), PageSize::Size2M, rw)?;
+    pt.map(va(0xffff_8000_0000_0000), pa(0x8000), PageSize::Size4K, rw)?;
+    drop(pt);
+
+    let all = va(0)..va(usize::MAX);
+    let leaves: Vec<_> = table.mappings(all.clone()).collect();
+    assert_eq!(
+        leaves,
+        [
+            (va(0x1000), pa(0x10_1000), 0x1000, rw, PageSize::Size4K),
+            (va(0x2000), pa(0x10_2000), 0x1000, rw, PageSize::Size4K),
+            (
+                va(0x3000),
+                pa(0x10_3000),
+                0x1000,
+                MappingFlags::READ,
+                PageSize::Size4K
+            ),
+            (
+                va(0x20_0000),
+                pa(0x40_0000),
+                0x20_0000,
+                rw,
+                PageSize::Size2M
+            ),
+            (
+                va(0xffff_8000_0000_0000),
+                pa(0x8000),
+                0x1000,
+                rw,
+                PageSize::Size4K
+            ),
+        ]
+    );
+    let runs: Vec<_> = table.mappings(all).coalesce().collect();
+    assert_eq!(
+        runs,
+        [
+            (va(0x1000), pa(0x10_1000), 0x2000, rw, PageSize::Size4K),
+            (
+                va(0x3000),
+                pa(0x10_3000),
+                0x1000,
+                MappingFlags::READ,
+                PageSize::Size4K
+            ),
+            (
+                va(0x20_0000),
+                pa(0x40_0000),
+                0x20_0000,
+                rw,
+                PageSize::Size2M
+            ),
+            (
+                va(0xffff_8000_0000_0000),
+                pa(0x8000),
+                0x1000,
+                rw,
+                PageSize::Size4K
+            ),
+        ]
+    );
+
+    // leaves overlapping the range are yielded whole
+    let leaves: Vec<_> = table.mappings(va(0x2000)..va(0x30_0000)).collect();
+    assert_eq!(leaves.len(), 3);
+    assert_eq!(leaves[2].0, va(0x20_0000));
+    assert_eq!(table.mappings(va(0x4000)..va(0x20_0000)).count(), 0);
+
+    run_upper_mappings_test_for::<A64PagingMetaData, A64PTE>(1 << 48, 0xffff_0000_0000_0000)?;
+    run_upper_mappings_test_for::<Sv39MetaData, Rv64PTE>(1 << 38, 0xffff_ffc0_0000_0000)?;
+    // the unused bottom of the ARMv7 `TTBR1` table
+    run_upper_mappings_test_for::<A32Ttbr1MetaData, A32PTE>(0, 0xc000_0000)?;
+    run_upper_mappings_test_for::<A32Ttbr1MetaData<1>, A32PTE>(0x1000, 0x8000_0000)?;
+    Ok(())
+}
+
+/// Checks that the iteration from the invalid address `hole`, e.g. in the hole
+/// after the lower half, resumes at the first valid one, at `upper`.
+fn run_upper_mappings_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>(
+    hole: usize,
+    upper: usize,
+) -> PagingResult<()> {
+    let flags = MappingFlags::READ;
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    table
+        .to_mut()
+        .map(va(upper), pa(0x2000), PageSize::Size4K, flags)?;
+    let vaddrs: Vec<_> = table
+        .mappings(va(hole)..va(usize::MAX))
+        .map(|(vaddr, ..)| vaddr)
+        .collect();
+    assert_eq!(vaddrs, [va(upper)]);
+    Ok(())
+}
+
+#[test]
+fn test_free_empty_tables() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    // two pages sharing the P3 and P2 tables, but not the P1 table
+    let mut pt = table.to_mut();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, flags)?;
+    pt.map(va(0x20_0000), pa(0x2000), PageSize::Size4K, flags)?;
+    pt.commit();
+    assert_eq!(handler.allocated.borrow().len(), 5);
+
+    // the tables are only deallocated on commit
+    pt.unmap_region(va(0x1000), 0x1000)?;
+    assert_eq!(handler.allocated.borrow().len(), 5);
+    pt.commit();
+    assert_eq!(handler.allocated.borrow().len(), 4);
+    assert_eq!(
+        pt.query(va(0x20_0000))?,
+        (pa(0x2000), flags, PageSize::Size4K)
+    );
+
+    // `unmap` keeps the tables until `compact`
+    pt.unmap(va(0x20_0000))?;
+    pt.commit();
+    assert_eq!(handler.allocated.borrow().len(), 4);
+    pt.compact(va(0), 0x4000_0000);
+    pt.commit();
+    assert_eq!(handler.allocated.borrow().len(), 1);
+
+    // the tables can be created again
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, flags)?;
+    drop(pt);
+    assert_eq!(handler.allocated.borrow().len(), 4);
+    assert_eq!(
+        table.query(va(0x1000))?,
+        (pa(0x1000), flags, PageSize::Size4K)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_split_huge_pages() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    let mut pt = table.to_mut();
+    pt.map(va(0x4000_0000), pa(0x8000_0000), PageSize::Size1G, rw)?;
+    pt.map(va(0x20_0000), pa(0x60_0000), PageSize::Size2M, rw)?;
+
+    // protect a part of a 2M page
+    pt.protect_region(va(0x20_1000), 0x1000, MappingFlags::READ)?;
+    assert_eq!(
+        pt.query(va(0x20_0000))?,
+        (pa(0x60_0000), rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x20_1000))?,
+        (pa(0x60_1000), MappingFlags::READ, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x3f_f000))?,
+        (pa(0x7f_f000), rw, PageSize::Size4K)
+    );
+
+    // unmap a part of a 1G page, which is split twice
+    pt.unmap_region(va(0x4020_0000), 0x20_1000)?;
+    assert_eq!(
+        pt.query(va(0x4000_0000))?,
+        (pa(0x8000_0000), rw, PageSize::Size2M)
+    );
+    assert_eq!(
+        pt.query(va(0x4020_0000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x4020_0000,
+            level: 2
+        })
+    );
+    assert_eq!(
+        pt.query(va(0x4040_0000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x4040_0000,
+            level: 3
+        })
+    );
+    assert_eq!(
+        pt.query(va(0x4040_1000))?,
+        (pa(0x8040_1000), rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x4060_0000))?,
+        (pa(0x8060_0000), rw, PageSize::Size2M)
+    );
+
+    // a base page cannot be split
+    let mut cursor = pt.cursor(va(0x20_0000));
+    assert_eq!(
+        cursor.split(),
+        Err(PagingError::NotAligned { vaddr: 0x20_0000 })
+    );
+    cursor.seek(va(0x4000_0000));
+    assert_eq!(cursor.split(), Ok(PageSize::Size4K));
+    Ok(())
+}
+
+fn run_collapse_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>()
+-> PagingResult<()> {
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    let mut pt = table.to_mut();
+    // 4K pages in the first 2M, and 2M pages in the rest of the first 1G
+    pt.map_region(
+        va(0),
+        |v| pa(v.as_usize() + 0x4000_0000),
+        0x20_0000,
+        flags,
+        false,
+    )?;
+    for i in 1..512 {
+        let vaddr = i * 0x20_0000;
+        pt.map(va(vaddr), pa(vaddr + 0x4000_0000), PageSize::Size2M, flags)?;
+    }
+    // a run with different flags is not collapsed
+    pt.map_region(
+        va(0x4000_0000),
+        |v| pa(v.as_usize()),
+        0x20_0000,
+        flags,
+        false,
+    )?;
+    pt.protect(va(0x4000_1000), MappingFlags::READ)?;
+    pt.commit();
+    let tables = handler.allocated.borrow().len();
+
+    // only the tables in the region are collapsed
+    pt.collapse(va(0x1000), 0x8000_0000 - 0x1000);
+    assert_eq!(
+        pt.query(va(0x1000))?,
+        (pa(0x4000_1000), flags, PageSize::Size4K)
+    );
+    pt.collapse(va(0), 0x8000_0000);
+    pt.commit();
+    assert_eq!(handler.allocated.borrow().len(), tables - 2);
+    assert_eq!(
+        pt.query(va(0x1000))?,
+        (pa(0x4000_1000), flags, PageSize::Size1G)
+    );
+    assert_eq!(
+        pt.query(va(0x4000_1000))?,
+        (pa(0x4000_1000), MappingFlags::READ, PageSize::Size4K)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_collapse() -> PagingResult<()> {
+    use page_table_entry::{aarch64::A64PTE, x86_64::X64PTE};
+    use page_table_multiarch::{aarch64::A64PagingMetaData, x86_64::X64PagingMetaData};
+
+    run_collapse_test_for::<X64PagingMetaData, X64PTE>()?;
+    run_collapse_test_for::<A64PagingMetaData, A64PTE>()?;
+    Ok(())
+}
+
+#[test]
+fn test_fork_cow() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let user_rw = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER;
+    let user_ro = MappingFlags::READ | MappingFlags::USER;
+    let kernel_rw = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut parent = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    let mut pt = parent.to_mut();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, user_rw)?;
+    pt.map(va(0x2000), pa(0x2000), PageSize::Size4K, user_ro)?;
+    pt.map(va(0x3000), pa(0x3000), PageSize::Size4K, kernel_rw)?;
+    pt.map(va(0x20_0000), pa(0x40_0000), PageSize::Size2M, user_rw)?;
+    pt.map(va(0x8000_0000), pa(0x8000), PageSize::Size4K, user_rw)?;
+    drop(pt);
+    let parent_tables = handler.allocated.borrow().len();
+
+    let mut child = parent.fork_cow(va(0)..va(0x4000_0000))?;
+    assert_eq!(handler.allocated.borrow().len(), parent_tables * 2 - 2);
+    for table in [&parent, &child] {
+        assert_eq!(
+            table.query(va(0x1000))?,
+            (pa(0x1000), user_ro, PageSize::Size4K)
+        );
+        assert_eq!(
+            table.query(va(0x2000))?,
+            (pa(0x2000), user_ro, PageSize::Size4K)
+        );
+        assert_eq!(
+            table.query(va(0x3000))?,
+            (pa(0x3000), kernel_rw, PageSize::Size4K)
+        );
+        assert_eq!(
+            table.query(va(0x20_1000))?,
+            (pa(0x40_1000), user_ro, PageSize::Size2M)
+        );
+    }
+    // out of the range
+    assert_eq!(
+        parent.query(va(0x8000_0000))?,
+        (pa(0x8000), user_rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        child.query(va(0x8000_0000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x8000_0000,
+            level: 1
+        })
+    );
+
+    // the child gets a copy, and the parent keeps the frame
+    let mut pt = child.to_mut();
+    assert_eq!(
+        pt.resolve_cow(va(0x1000), |paddr, size| {
+            assert_eq!((paddr, size), (pa(0x1000), PageSize::Size4K));
+            Some(pa(0x5000))
+        }),
+        Ok(true)
+    );
+    assert_eq!(
+        pt.query(va(0x1000))?,
+        (pa(0x5000), user_rw, PageSize::Size4K)
+    );
+    assert_eq!(pt.resolve_cow(va(0x1000), |_, _| None), Ok(false));
+    assert_eq!(pt.resolve_cow(va(0x2000), |_, _| None), Ok(false));
+    assert_eq!(
+        pt.resolve_cow(va(0x20_0000), |_, _| None),
+        Err(PagingError::NoMemory)
+    );
+    assert_eq!(
+        pt.resolve_cow(va(0x4000), |_, _| None),
+        Err(PagingError::NotMapped {
+            vaddr: 0x4000,
+            level: 3
+        })
+    );
+    drop(pt);
+    parent
+        .to_mut()
+        .resolve_cow(va(0x1000), |paddr, _| Some(paddr))?;
+    assert_eq!(
+        parent.query(va(0x1000))?,
+        (pa(0x1000), user_rw, PageSize::Size4K)
+    );
+
+    drop(child);
+    assert_eq!(handler.allocated.borrow().len(), parent_tables);
+
+    // the pages split from a huge page keep its copy-on-write mark, and so
+    // does the huge page they are collapsed into
+    let mut pt = parent.to_mut();
+    pt.protect_region(va(0x20_0000), 0x10_0000, user_ro)?;
+    assert_eq!(
+        pt.query(va(0x30_0000))?,
+        (pa(0x50_0000), user_ro, PageSize::Size4K)
+    );
+    pt.collapse(va(0x20_0000), 0x20_0000);
+    assert_eq!(
+        pt.query(va(0x30_0000))?,
+        (pa(0x50_0000), user_ro, PageSize::Size2M)
+    );
+    pt.protect_region(va(0x20_0000), 0x10_0000, user_ro)?;
+    assert_eq!(
+        pt.resolve_cow(va(0x20_1000), |paddr, size| {
+            assert_eq!((paddr, size), (pa(0x40_1000), PageSize::Size4K));
+            Some(pa(0x9000))
+        }),
+        Ok(true)
+    );
+    assert_eq!(
+        pt.query(va(0x20_1000))?,
+        (pa(0x9000), user_rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.resolve_cow(va(0x30_0000), |paddr, _| Some(paddr)),
+        Ok(true)
+    );
+    Ok(())
+}
+
+fn check_sw_bits_for<PTE: GenericPTE>(sw_bits: usize) {
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let rx = MappingFlags::READ | MappingFlags::EXECUTE;
+    let paddr = PhysAddr::from_usize(0x40_0000);
+    for is_huge in [false, true] {
+        let mut pte = PTE::new_page(paddr, rw, is_huge);
+        pte.set_sw_bits(usize::MAX);
+        assert_eq!(pte.sw_bits(), sw_bits);
+        assert!(!pte.is_cow());
+        pte.set_cow(true);
+        pte.set_sw_bits(0b1);
+
+        // `set_flags` keeps the bits it does not control
+        pte.set_flags(rx, is_huge);
+        assert_eq!(pte.flags() & (rw | rx), rx);
+        assert_eq!(pte.paddr(), paddr);
+        assert_eq!(pte.sw_bits(), 0b1 & sw_bits);
+        assert!(pte.is_cow());
+        pte.set_cow(false);
+        pte.set_sw_bits(0);
+        assert_eq!(pte.bits(), PTE::new_page(paddr, rx, is_huge).bits());
+    }
+}
+
+/// Checks that splitting and collapsing a huge page keep its software bits.
+fn run_sw_bits_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>(
+    sw_bits: usize,
+) -> PagingResult<()> {
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let mut pt = table.to_mut();
+
+    pt.map(
+        va(0x20_0000),
+        PhysAddr::from_usize(0x40_0000),
+        PageSize::Size2M,
+        rw,
+    )?;
+    pt.set_sw_bits(va(0x20_0000), sw_bits)?;
+    pt.protect_region(va(0x20_0000), 0x1000, MappingFlags::READ)?;
+    assert_eq!(pt.sw_bits(va(0x20_0000)), Ok(sw_bits));
+    assert_eq!(pt.sw_bits(va(0x3f_f000)), Ok(sw_bits));
+
+    // pages with other software bits are not collapsed
+    pt.protect(va(0x20_0000), rw)?;
+    pt.set_sw_bits(va(0x20_0000), 0)?;
+    pt.collapse(va(0x20_0000), 0x20_0000);
+    assert_eq!(pt.query(va(0x20_0000))?.2, PageSize::Size4K);
+    pt.set_sw_bits(va(0x20_0000), sw_bits)?;
+    pt.collapse(va(0x20_0000), 0x20_0000);
+    assert_eq!(pt.query(va(0x20_0000))?.2, PageSize::Size2M);
+    assert_eq!(pt.sw_bits(va(0x20_0000)), Ok(sw_bits));
+    Ok(())
+}
+
+#[test]
+fn test_sw_bits() -> PagingResult<()> {
+    use page_table_entry::{
+        aarch64::{A64PTE, A64S2PTE},
+        arm::A32PTE,
+        loongarch64::LA64PTE,
+        riscv::{Rv64PTE, Sv32PTE},
+        x86_64::{EPTEntry, X64PTE, X86PTE32},
+    };
+    use page_table_multiarch::{
+        aarch64::A64PagingMetaData, loongarch64::LA64MetaData, riscv::Sv39MetaData,
+        x86_64::X64PagingMetaData,
+    };
+
+    check_sw_bits_for::<X64PTE>(0x1ff);
+    check_sw_bits_for::<X86PTE32>(0b11);
+    check_sw_bits_for::<EPTEntry>(0x1f);
+    check_sw_bits_for::<Rv64PTE>(0b1);
+    check_sw_bits_for::<Sv32PTE>(0b1);
+    check_sw_bits_for::<A64PTE>(0b111);
+    check_sw_bits_for::<A64S2PTE>(0b111);
+    check_sw_bits_for::<LA64PTE>(0b11);
+    check_sw_bits_for::<A32PTE>(0);
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let vaddr = VirtAddr::from_usize(0x1000);
+    let mut pt = table.to_mut();
+    assert_eq!(
+        pt.set_sw_bits(vaddr, 0b101),
+        Err(PagingError::NotMapped {
+            vaddr: 0x1000,
+            level: 0
+        })
+    );
+    pt.map(
+        vaddr,
+        PhysAddr::from_usize(0x2000),
+        PageSize::Size4K,
+        MappingFlags::READ,
+    )?;
+    assert_eq!(pt.set_sw_bits(vaddr, 0b101), Ok(PageSize::Size4K));
+    pt.protect(vaddr, MappingFlags::READ | MappingFlags::WRITE)?;
+    assert_eq!(pt.sw_bits(vaddr), Ok(0b101));
+
+    run_sw_bits_test_for::<X64PagingMetaData, X64PTE>(0x1ff)?;
+    run_sw_bits_test_for::<A64PagingMetaData, A64PTE>(0b111)?;
+    run_sw_bits_test_for::<Sv39MetaData, Rv64PTE>(0b1)?;
+    run_sw_bits_test_for::<LA64MetaData, LA64PTE>(0b11)?;
+    Ok(())
+}
+
+fn run_harvest_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>()
+-> PagingResult<()> {
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let handler = TrackPagingHandler::<M>::default();
+    let mut table = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+
+    let mut pt = table.to_mut();
+    pt.map_region(va(0x1000), |v| pa(v.as_usize()), 0x3000, rw, false)?;
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, rw)?;
+    pt.map(va(0x4000_0000), pa(0x1000), PageSize::Size4K, rw)?;
+
+    // pages are mapped as accessed and dirty, and the harvest clears them
+    let mut dirty = Vec::new();
+    pt.harvest_dirty(va(0x2000), 0x20_0000, |vaddr, paddr, size| {
+        dirty.push((vaddr, paddr, size))
+    });
+    assert_eq!(
+        dirty,
+        [
+            (va(0x2000), pa(0x2000), PageSize::Size4K),
+            (va(0x3000), pa(0x3000), PageSize::Size4K),
+            (va(0x20_0000), pa(0x20_0000), PageSize::Size2M),
+        ]
+    );
+    let mut dirty = Vec::new();
+    pt.harvest_dirty(va(0), 0x8000_0000, |vaddr, _, _| dirty.push(vaddr));
+    assert_eq!(dirty, [va(0x1000), va(0x4000_0000)]);
+
+    let mut accessed = 0;
+    pt.harvest_accessed(va(0), 0x8000_0000, |_, _, _| accessed += 1);
+    assert_eq!(accessed, 5);
+    pt.harvest_accessed(va(0), 0x8000_0000, |_, _, _| accessed += 1);
+    assert_eq!(accessed, 5);
+
+    // the flags do not change with the accessed and dirty state
+    assert_eq!(pt.query(va(0x1000))?, (pa(0x1000), rw, PageSize::Size4K));
+
+    // and changing the flags does not make the pages accessed or dirty
+    pt.protect(va(0x1000), rw)?;
+    pt.protect(va(0x20_0000), rw)?;
+    pt.protect_region(va(0x2000), 0x2000, MappingFlags::READ)?;
+    pt.harvest_dirty(va(0), 0x8000_0000, |vaddr, _, _| {
+        panic!("{vaddr:?} is dirty")
+    });
+    pt.harvest_accessed(va(0), 0x8000_0000, |vaddr, _, _| {
+        panic!("{vaddr:?} is accessed")
+    });
+
+    // a collapsed block is accessed and dirty if one of its pages is
+    pt.map_region(va(0x40_0000), |v| pa(v.as_usize()), 0x20_0000, rw, false)?;
+    pt.harvest_accessed(va(0x40_0000), 0x5000, |_, _, _| {});
+    pt.harvest_accessed(va(0x40_6000), 0x1f_a000, |_, _, _| {});
+    pt.harvest_dirty(va(0x40_0000), 0x5000, |_, _, _| {});
+    pt.harvest_dirty(va(0x40_6000), 0x1f_a000, |_, _, _| {});
+    pt.collapse(va(0x40_0000), 0x20_0000);
+    let mut dirty = Vec::new();
+    pt.harvest_dirty(va(0x40_0000), 0x20_0000, |vaddr, paddr, size| {
+        dirty.push((vaddr, paddr, size))
+    });
+    assert_eq!(dirty, [(va(0x40_0000), pa(0x40_0000), PageSize::Size2M)]);
+    let mut accessed = 0;
+    pt.harvest_accessed(va(0x40_0000), 0x20_0000, |_, _, _| accessed += 1);
+    assert_eq!(accessed, 1);
+
+    // but not if one page is only accessed and another only dirty
+    pt.map_region(va(0x60_0000), |v| pa(v.as_usize()), 0x20_0000, rw, false)?;
+    pt.harvest_accessed(va(0x60_0000), 0x6000, |_, _, _| {});
+    pt.harvest_accessed(va(0x60_7000), 0x1f_9000, |_, _, _| {});
+    pt.harvest_dirty(va(0x60_0000), 0x5000, |_, _, _| {});
+    pt.harvest_dirty(va(0x60_6000), 0x1f_a000, |_, _, _| {});
+    pt.collapse(va(0x60_0000), 0x20_0000);
+    assert_eq!(pt.query(va(0x60_0000))?.2, PageSize::Size4K);
+    Ok(())
+}
+
+/// Checks that changing the flags of an entry keeps it clean and, with an
+/// accessed bit, not accessed.
+fn check_harvested_flags_for<PTE: GenericPTE>() {
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let mut pte = PTE::new_page(PhysAddr::from_usize(0x1000), rw, false);
+    pte.clear_accessed();
+    pte.clear_dirty();
+    let accessed = pte.is_accessed();
+    pte.set_flags(rw, false);
+    assert_eq!(pte.is_accessed(), accessed);
+    assert!(!pte.is_dirty());
+    pte.set_flags(MappingFlags::READ, false);
+    assert_eq!(pte.is_accessed(), accessed);
+    assert!(!pte.is_dirty());
+}
+
+#[test]
+fn test_harvest() -> PagingResult<()> {
+    use page_table_entry::{
+        aarch64::{A64PTE, A64S2PTE},
+        loongarch64::LA64PTE,
+        riscv::{Rv64PTE, Sv32PTE},
+        x86_64::X64PTE,
+    };
+    use page_table_multiarch::{
+        aarch64::{A64PagingMetaData, A64Stage2MetaData},
+        riscv::Sv39MetaData,
+        x86_64::X64PagingMetaData,
+    };
+
+    // x86 pages are mapped clean and not accessed
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table =
+        PageTable64::<NoFlushMetaData<X64PagingMetaData>, X64PTE, _>::try_new_in(&handler)?;
+    let mut pt = table.to_mut();
+    let vaddr = VirtAddr::from_usize(0x1000);
+    pt.map(
+        vaddr,
+        PhysAddr::from_usize(0x1000),
+        PageSize::Size4K,
+        MappingFlags::READ,
+    )?;
+    pt.harvest_accessed(vaddr, 0x1000, |_, _, _| panic!("not accessed"));
+    drop(pt);
+
+    run_harvest_test_for::<A64PagingMetaData, A64PTE>()?;
+    run_harvest_test_for::<A64Stage2MetaData, A64S2PTE>()?;
+    run_harvest_test_for::<Sv39MetaData, Rv64PTE>()?;
+
+    check_harvested_flags_for::<X64PTE>();
+    check_harvested_flags_for::<A64PTE>();
+    check_harvested_flags_for::<A64S2PTE>();
+    check_harvested_flags_for::<Rv64PTE>();
+    check_harvested_flags_for::<Sv32PTE>();
+    check_harvested_flags_for::<LA64PTE>();
+    Ok(())
+}
+
+#[test]
+fn test_ept_mem_type() {
+    use page_table_entry::x86_64::{EPTEntry, EPTFlags, EPTMemType};
+
+    // only device memory ignores the guest PAT memory type
+    for (flags, mem_type, ignore_pat) in [
+        (MappingFlags::READ, EPTMemType::WriteBack, false),
+        (
+            MappingFlags::READ | MappingFlags::UNCACHED,
+            EPTMemType::Uncached,
+            false,
+        ),
+        (
+            MappingFlags::READ | MappingFlags::DEVICE,
+            EPTMemType::Uncached,
+            true,
+        ),
+    ] {
+        let pte = EPTEntry::new_page(PhysAddr::from_usize(0x1000), flags, false);
+        let ept_flags = EPTFlags::from_bits_truncate(pte.bits() as u64);
+        assert_eq!(ept_flags.mem_type(), Some(mem_type));
+        assert_eq!(ept_flags.contains(EPTFlags::IGNORE_PAT), ignore_pat);
+        assert_eq!(pte.flags(), flags);
+    }
+}
+
+/// `no_access` is whether the entries can be made non-present by setting empty
+/// flags.
+fn check_swap_for<PTE: GenericPTE>(no_access: bool) {
+    let payload = usize::MAX >> (usize::BITS - PTE::SWAP_PAYLOAD_BITS);
+    for payload in [0, 0x1234, payload] {
+        let pte = PTE::new_swap(payload);
+        assert!(!pte.is_present());
+        assert!(!pte.is_unused());
+        assert_eq!(pte.swap_payload(), Some(payload));
+    }
+
+    let paddr = PhysAddr::from_usize(0x40_0000);
+    assert_eq!(PTE::new_table(paddr).swap_payload(), None);
+    for flags in [
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER,
+        MappingFlags::READ,
+    ] {
+        let mut pte = PTE::new_page(paddr, flags, false);
+        pte.set_cow(true);
+        pte.set_sw_bits(usize::MAX);
+        assert_eq!(pte.swap_payload(), None);
+        if no_access {
+            // non-present entries left by `protect` are not swap entries
+            pte.set_flags(MappingFlags::empty(), false);
+            assert!(!pte.is_present());
+            assert_eq!(pte.swap_payload(), None);
+        }
+    }
+}
+
+#[test]
+fn test_swap_entries() -> PagingResult<()> {
+    use page_table_entry::{
+        aarch64::{A64PTE, A64S2PTE},
+        arm::A32PTE,
+        loongarch64::LA64PTE,
+        riscv::{Rv64PTE, Sv32PTE},
+        x86_64::{EPTEntry, X64PTE, X86PTE32},
+    };
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    check_swap_for::<X64PTE>(true);
+    check_swap_for::<X86PTE32>(true);
+    check_swap_for::<EPTEntry>(true);
+    check_swap_for::<Rv64PTE>(false);
+    check_swap_for::<Sv32PTE>(false);
+    check_swap_for::<A64PTE>(true);
+    check_swap_for::<A64S2PTE>(true);
+    check_swap_for::<LA64PTE>(true);
+    check_swap_for::<A32PTE>(true);
+
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+
+    let mut pt = table.to_mut();
+    pt.map(
+        va(0x1000),
+        PhysAddr::from_usize(0x1000),
+        PageSize::Size4K,
+        rw,
+    )?;
+    assert_eq!(
+        pt.set_swap(va(0x1000), 1),
+        Err(PagingError::AlreadyMapped {
+            vaddr: 0x1000,
+            level: 3,
+            entry: X64PTE::new_page(PhysAddr::from_usize(0x1000), rw, false).bits()
+        })
+    );
+    let payload = usize::MAX >> (usize::BITS - X64PTE::SWAP_PAYLOAD_BITS);
+    assert_eq!(
+        pt.set_swap(va(0x2000), payload + 1),
+        Err(PagingError::InvalidSwapPayload(payload + 1))
+    );
+    pt.set_swap(va(0x2000), 2)?;
+    pt.set_swap(va(0x2000), 3)?;
+    assert_eq!(pt.query(va(0x2000)), Err(PagingError::Swapped(3)));
+    assert_eq!(
+        pt.query(va(0x3000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x3000,
+            level: 3
+        })
+    );
+    assert_eq!(
+        pt.map(
+            va(0x2000),
+            PhysAddr::from_usize(0x2000),
+            PageSize::Size4K,
+            rw
+        ),
+        Err(PagingError::AlreadyMapped {
+            vaddr: 0x2000,
+            level: 3,
+            entry: X64PTE::new_swap(3).bits()
+        })
+    );
+
+    // swap entries survive unmapping, and keep their tables
+    assert_eq!(pt.unmap(va(0x2000)), Err(PagingError::Swapped(3)));
+    pt.unmap(va(0x1000))?;
+    pt.compact(va(0), 0x4000_0000);
+    assert_eq!(pt.cursor(va(0x2000)).query(), Err(PagingError::Swapped(3)));
+    assert_eq!(pt.mappings(va(0)..va(0x4000_0000)).count(), 0);
+
+    assert_eq!(
+        pt.take_swap(va(0x1000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x1000,
+            level: 3
+        })
+    );
+    assert_eq!(pt.take_swap(va(0x2000)), Ok(3));
+    assert_eq!(
+        pt.query(va(0x2000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x2000,
+            level: 3
+        })
+    );
+    pt.compact(va(0), 0x4000_0000);
+    drop(pt);
+    assert_eq!(handler.allocated.borrow().len(), 1);
+    Ok(())
+}
+
+thread_local! {
+    /// The TLB flushes done by [`RecordFlushMetaData`], as `(asid, vaddr)`.
+    static FLUSHES: RefCell<Vec<(Option<u16>, Option<usize>)>> = const { RefCell::new(Vec::new()) };
+    /// The root installed by [`RecordFlushMetaData`], with its ASID.
+    static CURRENT_ROOT: Cell<Option<(PhysAddr, Option<u16>)>> = const { Cell::new(None) };
+}
+
+/// Wraps the metadata of an architecture, and records the TLB flushes and the
+/// installed root instead of doing them.
+struct RecordFlushMetaData<M: PagingMetaData>(PhantomData<M>);
+
+impl<M: PagingMetaData> PagingMetaData for RecordFlushMetaData<M> {
+    const LEVELS: usize = M::LEVELS;
+    const PA_MAX_BITS: usize = M::PA_MAX_BITS;
+    const VA_MAX_BITS: usize = M::VA_MAX_BITS;
+    type VirtAddr = M::VirtAddr;
+
+    fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        FLUSHES.with_borrow_mut(|f| f.push((None, vaddr.map(Into::into))));
+    }
+
+    fn flush_tlb_asid(asid: u16, vaddr: Option<Self::VirtAddr>) {
+        FLUSHES.with_borrow_mut(|f| f.push((Some(asid), vaddr.map(Into::into))));
+    }
+
+    unsafe fn activate(root: PhysAddr, asid: Option<u16>) {
+        CURRENT_ROOT.set(Some((root, asid)));
+    }
+
+    fn read_current_root() -> Option<PhysAddr> {
+        CURRENT_ROOT.get().map(|(root, _)| root)
+    }
+}
+
+#[test]
+fn test_asid() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = RecordFlushMetaData<X64PagingMetaData>;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let flags = MappingFlags::READ;
+    let map = |table: &mut PageTable64<M, X64PTE, _>, vaddr| {
+        let paddr = PhysAddr::from_usize(vaddr);
+        let vaddr = VirtAddr::from_usize(vaddr);
+        table.to_mut().map(vaddr, paddr, PageSize::Size4K, flags)
+    };
+
+    assert_eq!(table.asid(), None);
+    map(&mut table, 0x1000)?;
+    table.set_asid(Some(7));
+    assert_eq!(table.asid(), Some(7));
+    map(&mut table, 0x2000)?;
+    let mut pt = table.to_mut();
+    pt.protect_region(VirtAddr::from_usize(0), 0x1_0000, flags)?;
+    pt.commit();
+    assert_eq!(
+        FLUSHES.take(),
+        [
+            (None, Some(0x1000)),
+            (Some(7), Some(0x2000)),
+            (Some(7), Some(0x1000)),
+            (Some(7), Some(0x2000)),
+        ]
+    );
+
+    // too many pages to flush one by one
+    let start = VirtAddr::from_usize(0x10_0000);
+    pt.map_region(
+        start,
+        |v| PhysAddr::from_usize(v.as_usize()),
+        0x4_0000,
+        flags,
+        false,
+    )?;
+    pt.commit();
+    assert_eq!(FLUSHES.take(), [(Some(7), None)]);
+    Ok(())
+}
+
+thread_local! {
+    /// The ranges flushed by [`RecordRangeMetaData`], as `(start, end, stride)`.
+    static RANGES: RefCell<Vec<(usize, usize, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Like [`RecordFlushMetaData`], but records the ranges to flush as well.
+struct RecordRangeMetaData<M: PagingMetaData>(PhantomData<M>);
+
+impl<M: PagingMetaData> PagingMetaData for RecordRangeMetaData<M> {
+    const LEVELS: usize = M::LEVELS;
+    const PA_MAX_BITS: usize = M::PA_MAX_BITS;
+    const VA_MAX_BITS: usize = M::VA_MAX_BITS;
+    type VirtAddr = M::VirtAddr;
+
+    fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        FLUSHES.with_borrow_mut(|f| f.push((None, vaddr.map(Into::into))));
+    }
+
+    fn flush_tlb_range(
+        start: Self::VirtAddr,
+        end: Self::VirtAddr,
+        stride: usize,
+        _asid: Option<u16>,
+    ) {
+        RANGES.with_borrow_mut(|r| r.push((start.into(), end.into(), stride)));
+    }
+}
+
+#[test]
+fn test_flush_range() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    type M = RecordRangeMetaData<X64PagingMetaData>;
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+    let flags = MappingFlags::READ;
+
+    // adjacent pages of the same size are flushed as one range
+    let mut pt = table.to_mut();
+    pt.map_region(va(0x10_0000), |v| pa(v.as_usize()), 0x4_0000, flags, false)?;
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, flags)?;
+    pt.protect(va(0x12_0000), flags)?;
+    pt.map(va(0xf_f000), pa(0xf_f000), PageSize::Size4K, flags)?;
+    pt.commit();
+    assert_eq!(
+        RANGES.take(),
+        [
+            (0xf_f000, 0x14_0000, 0x1000),
+            (0x20_0000, 0x40_0000, 0x20_0000),
+        ]
+    );
+
+    pt.unmap_region(va(0x20_0000), 0x20_0000)?;
+    pt.unmap_region(va(0x10_0000), 0x4_0000)?;
+    pt.commit();
+    assert_eq!(
+        RANGES.take(),
+        [
+            (0x20_0000, 0x40_0000, 0x20_0000),
+            (0x10_0000, 0x14_0000, 0x1000),
+        ]
+    );
+
+    // a split huge page is flushed as a whole
+    pt.map(va(0x40_0000), pa(0x40_0000), PageSize::Size2M, flags)?;
+    pt.commit();
+    RANGES.take();
+    pt.protect_region(va(0x40_1000), 0x1000, flags | MappingFlags::WRITE)?;
+    pt.commit();
+    assert_eq!(
+        RANGES.take(),
+        [
+            (0x40_0000, 0x60_0000, 0x20_0000),
+            (0x40_1000, 0x40_2000, 0x1000),
+        ]
+    );
+    assert_eq!(FLUSHES.take(), []);
+
+    // too many separate ranges
+    for i in 0..5 {
+        pt.map(va(i << 30), pa(0x1000), PageSize::Size4K, flags)?;
+    }
+    pt.commit();
+    assert_eq!(RANGES.take(), []);
+    assert_eq!(FLUSHES.take(), [(None, None)]);
+    Ok(())
+}
+
+/// A shootdown recorded by [`ShootdownHandler`]: the ASID, the ranges flushed
+/// or `None` for a full flush, and the number of frames allocated at the time.
+type Shootdown = (Option<u16>, Option<Vec<TlbRange>>, usize);
+
+/// Wraps [`TrackPagingHandler`], and records the shootdowns.
+struct ShootdownHandler<'a, M: PagingMetaData> {
+    inner: &'a TrackPagingHandler<M>,
+    shootdowns: RefCell<Vec<Shootdown>>,
+}
+
+impl<M: PagingMetaData> PagingHandler for &ShootdownHandler<'_, M> {
+    fn alloc_frame(&self) -> Option<PhysAddr> {
+        self.inner.alloc_frame()
+    }
+
+    fn dealloc_frame(&self, paddr: PhysAddr) {
+        self.inner.dealloc_frame(paddr)
+    }
+
+    fn phys_to_virt(&self, paddr: PhysAddr) -> VirtAddr {
+        self.inner.phys_to_virt(paddr)
+    }
+
+    fn shootdown(&self, asid: Option<u16>, flush: TlbFlush<'_>) {
+        let ranges = match flush {
+            TlbFlush::Ranges(ranges) => Some(ranges.to_vec()),
+            TlbFlush::All => None,
+        };
+        let allocated = self.inner.allocated.borrow().len();
+        self.shootdowns.borrow_mut().push((asid, ranges, allocated));
+    }
+}
+
+#[test]
+fn test_shootdown() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    let track = TrackPagingHandler::<X64PagingMetaData>::default();
+    let handler = ShootdownHandler {
+        inner: &track,
+        shootdowns: RefCell::default(),
+    };
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+    let flags = MappingFlags::READ;
+
+    let mut pt = table.to_mut();
+    pt.map_region(va(0x1000), |v| pa(v.as_usize()), 0x2000, flags, false)?;
+    pt.commit();
+    // nothing to flush
+    pt.commit();
+    let range = TlbRange {
+        start: 0x1000,
+        end: 0x3000,
+        stride: 0x1000,
+    };
+    assert_eq!(handler.shootdowns.take(), [(None, Some(vec![range]), 4)]);
+
+    // the freed tables are deallocated after the shootdown
+    pt.unmap_region(va(0x1000), 0x2000)?;
+    drop(pt);
+    assert_eq!(handler.shootdowns.take(), [(None, None, 4)]);
+    assert_eq!(track.allocated.borrow().len(), 1);
+
+    table.set_asid(Some(3));
+    let mut pt = table.to_mut();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, flags)?;
+    drop(pt);
+    assert_eq!(handler.shootdowns.borrow()[0].0, Some(3));
+    Ok(())
+}
+
+#[test]
+fn test_detach() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    let track = TrackPagingHandler::<X64PagingMetaData>::default();
+    let handler = ShootdownHandler {
+        inner: &track,
+        shootdowns: RefCell::default(),
+    };
+    type M = RecordFlushMetaData<X64PagingMetaData>;
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let flags = MappingFlags::READ;
+
+    table.detach();
+    assert!(table.is_detached());
+    let mut pt = table.to_mut();
+    pt.map_region(
+        va(0x1000),
+        |v| PhysAddr::from_usize(v.as_usize()),
+        0x2000,
+        flags,
+        false,
+    )?;
+    pt.commit();
+    pt.unmap_region(va(0x1000), 0x2000)?;
+    drop(pt);
+    assert_eq!(FLUSHES.take(), []);
+    assert_eq!(handler.shootdowns.take(), []);
+    assert_eq!(track.allocated.borrow().len(), 1);
+
+    table.set_asid(Some(5));
+    assert!(!table.is_current());
+    unsafe { table.activate() };
+    assert!(!table.is_detached());
+    assert!(table.is_current());
+    assert_eq!(CURRENT_ROOT.get(), Some((table.root_paddr(), Some(5))));
+    assert_eq!(FLUSHES.take(), [(Some(5), None)]);
+    table.to_mut().map(
+        va(0x1000),
+        PhysAddr::from_usize(0x1000),
+        PageSize::Size4K,
+        flags,
+    )?;
+    assert_eq!(FLUSHES.take(), [(Some(5), Some(0x1000))]);
+    assert_eq!(handler.shootdowns.take().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_errors() -> PagingResult<()> {
+    use page_table_entry::x86_64::X64PTE;
+    use page_table_multiarch::x86_64::X64PagingMetaData;
+
+    let handler = TrackPagingHandler::<X64PagingMetaData>::default();
+    type M = NoFlushMetaData<X64PagingMetaData>;
+    let mut table = PageTable64::<M, X64PTE, _>::try_new_in(&handler)?;
+    let mut pt = table.to_mut();
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+    let flags = MappingFlags::READ;
+
+    // non-canonical
+    assert_eq!(
+        pt.map(va(0x8000_0000_0000), pa(0x1000), PageSize::Size4K, flags),
+        Err(PagingError::InvalidVirtAddr(0x8000_0000_0000))
+    );
+    assert_eq!(
+        pt.map(va(0x1000), pa(1 << 52), PageSize::Size4K, flags),
+        Err(PagingError::InvalidPhysAddr(pa(1 << 52)))
+    );
+    assert_eq!(
+        pt.map_region(va(0x1234), |v| pa(v.as_usize()), 0x1000, flags, false),
+        Err(PagingError::NotAligned { vaddr: 0x1234 })
+    );
+    assert_eq!(
+        PageSize::try_from(0x3000),
+        Err(PagingError::UnsupportedPageSize(0x3000))
+    );
+
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, flags)?;
+    let huge = PagingError::MappedToHugePage {
+        vaddr: 0x20_1000,
+        level: 2,
+    };
+    assert_eq!(
+        pt.map(va(0x20_1000), pa(0x1000), PageSize::Size4K, flags),
+        Err(huge)
+    );
+    assert_eq!(
+        huge.to_string(),
+        "0x201000 is mapped by a huge page at level 2"
+    );
+    assert_eq!(
+        PagingError::NotMapped {
+            vaddr: 0x1000,
+            level: 3
+        }
+        .to_string(),
+        "0x1000 is not mapped at level 3"
+    );
+    Ok(())
+}
+
+fn run_split_test_for<M: PagingMetaData<VirtAddr = VirtAddr>, PTE: GenericPTE>(
+    kernel_base: usize,
+) -> PagingResult<()>
+where
+    NoFlushMetaData<M>: SplitSpaceMetaData<VirtAddr = VirtAddr>,
+{
+    use page_table_multiarch::SplitAddressSpace;
+
+    type S<'a, M, PTE> = SplitAddressSpace<'a, NoFlushMetaData<M>, PTE, &'a TrackPagingHandler<M>>;
+
+    let handler = TrackPagingHandler::<M>::default();
+    let mut kernel = PageTable64::<NoFlushMetaData<M>, PTE, _>::try_new_in(&handler)?;
+    let va = VirtAddr::from_usize;
+    let pa = PhysAddr::from_usize;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    kernel.to_mut().map(
+        va(kernel_base + 0x1000),
+        pa(0x8000),
+        PageSize::Size4K,
+        flags,
+    )?;
+
+    let mut space = SplitAddressSpace::try_new_in(&kernel, &handler)?;
+    assert!(S::<M, PTE>::is_kernel_vaddr(va(kernel_base)));
+    assert!(!S::<M, PTE>::is_kernel_vaddr(va(0x1000)));
+    space.map(va(0x1000), pa(0x9000), PageSize::Size4K, flags)?;
+    // the kernel half is changed through the kernel page table only
+    let kernel_vaddr = kernel_base + 0x2000;
+    assert_eq!(
+        space.map(va(kernel_vaddr), pa(0x9000), PageSize::Size4K, flags),
+        Err(PagingError::InvalidVirtAddr(kernel_vaddr))
+    );
+    assert_eq!(
+        space.unmap(va(kernel_vaddr)),
+        Err(PagingError::InvalidVirtAddr(kernel_vaddr))
+    );
+    assert_eq!(
+        space.query(va(0x1000)),
+        Ok((pa(0x9000), flags, PageSize::Size4K))
+    );
+    assert_eq!(
+        space.query(va(kernel_base + 0x1000)),
+        Ok((pa(0x8000), flags, PageSize::Size4K))
+    );
+    assert_eq!(
+        space.kernel().query(va(0x1000)),
+        Err(PagingError::NotMapped {
+            vaddr: 0x1000,
+            level: 0
+        })
+    );
+    // the root entry is shared again on activation, without freeing the
+    // kernel tables
+    unsafe { space.activate() };
+    if M::SPLIT_ROOTS {
+        assert!(space.user().query(va(kernel_base + 0x1000)).is_err());
+    } else {
+        // the root entry is shared
+        assert!(space.user().query(va(kernel_base + 0x1000)).is_ok());
+    }
+    assert_eq!(
+        space.unmap(va(0x1000)),
+        Ok((pa(0x9000), flags, PageSize::Size4K))
+    );
+    drop(space);
+
+    // the shared kernel tables are kept
+    assert_eq!(
+        kernel.query(va(kernel_base + 0x1000)),
+        Ok((pa(0x8000), flags, PageSize::Size4K))
+    );
+    drop(kernel);
+    assert!(handler.allocated.borrow().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_split_address_space() -> PagingResult<()> {
+    use page_table_entry::aarch64::A64PTE;
+    use page_table_multiarch::aarch64::A64PagingMetaData;
+
+    run_split_test_for::<A64PagingMetaData, A64PTE>(0xffff_8000_0000_0000)?;
+    // a single root shares the kernel half by `copy_from`
+    #[cfg(feature = "copy-from")]
+    run_split_test_for::<
+        page_table_multiarch::x86_64::X64PagingMetaData,
+        page_table_entry::x86_64::X64PTE,
+    >(0xffff_8000_0000_0000)?;
+    Ok(())
+}